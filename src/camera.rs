@@ -1,7 +1,5 @@
 use cgmath::{Angle, InnerSpace, Matrix4, Point3, Rad, Vector3};
 
-use crate::{HEIGHT, WIDTH};
-
 pub struct Camera {
     pos: Point3<f32>,
     dir: Vector3<f32>,
@@ -10,8 +8,6 @@ pub struct Camera {
     move_speed: f32,
     look_sensitivity: f64,
 
-    current_x: f64,
-    current_y: f64,
     azimut: f64,
     zenit: f64,
 
@@ -28,8 +24,6 @@ impl Camera {
             up: Vector3::new(0., 1., 0.),
             move_speed,
             look_sensitivity,
-            current_x: WIDTH as f64 / 2.,
-            current_y: HEIGHT as f64 / 2.,
             azimut: 0.,
             zenit: 0.,
             changed: true,
@@ -65,10 +59,8 @@ impl Camera {
         self.strafe_right(-d);
     }
 
-    pub fn adjust_look(&mut self, new_x: f64, new_y: f64) {
-        let dx = self.current_x - new_x;
-        let dy = self.current_y - new_y;
-
+    /// `dx` and `dy` are the offsets of the cursor from its resting position
+    pub fn adjust_look(&mut self, dx: f64, dy: f64) {
         let x_offset = dx * self.look_sensitivity;
         let y_offset = dy * self.look_sensitivity;
 
@@ -0,0 +1,476 @@
+use std::path::Path;
+
+use cgmath::{
+    InnerSpace, Matrix, Matrix3, Matrix4, Point3, SquareMatrix, Transform, Vector3, Vector4,
+};
+use eyre::{bail, ensure, Result, WrapErr};
+use gltf::{
+    image::Format,
+    material::AlphaMode,
+    mesh::Mode,
+    texture::{MinFilter, WrappingMode},
+    Node,
+};
+
+use crate::{
+    raster::Blend,
+    solid::{ColorSpace, Filter, Material, Mesh, Pbr, Solid, Texture, Wrap},
+};
+
+/// Loads the default scene (or the first one) of a `.gltf` or `.glb` file.
+///
+/// Node transforms are baked into the vertices, so the whole scene becomes a single [`Solid`].
+/// Buffers and images are only read from the file itself or from paths relative to it.
+/// Primitives made of points or lines are skipped and returned next to the solid.
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<(Solid, Vec<SkippedPrimitive>)> {
+    let path = path.as_ref();
+    let (document, buffers, images) =
+        gltf::import(path).wrap_err_with(|| format!("Couldn't load '{}'", path.display()))?;
+
+    let mut meshes = Vec::new();
+    let mut skipped = Vec::new();
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next());
+
+    if let Some(scene) = scene {
+        for node in scene.nodes() {
+            load_node(
+                &node,
+                Matrix4::identity(),
+                &buffers,
+                &images,
+                &mut meshes,
+                &mut skipped,
+            )?;
+        }
+    }
+
+    Ok((Solid::new(meshes), skipped))
+}
+
+/// Primitive of the file that can't be rasterized
+#[derive(Debug, Clone)]
+pub struct SkippedPrimitive {
+    /// Name of the glTF mesh
+    pub mesh: String,
+    /// Index of the primitive in the mesh
+    pub index: usize,
+    pub mode: Mode,
+}
+
+fn load_node(
+    node: &Node,
+    parent_transform: Matrix4<f32>,
+    buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data],
+    meshes: &mut Vec<Mesh>,
+    skipped: &mut Vec<SkippedPrimitive>,
+) -> Result<()> {
+    let transform = parent_transform * Matrix4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        let name = mesh.name().unwrap_or("<unnamed>");
+        for primitive in mesh.primitives() {
+            // Points and lines can't be rasterized, the rest of the mesh still can
+            if let mode @ (Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip) =
+                primitive.mode()
+            {
+                skipped.push(SkippedPrimitive {
+                    mesh: name.to_string(),
+                    index: primitive.index(),
+                    mode,
+                });
+                continue;
+            }
+
+            let mesh = load_primitive(&primitive, transform, buffers, images)
+                .wrap_err_with(|| format!("Couldn't load mesh '{name}'"))?;
+            meshes.push(mesh);
+        }
+    }
+
+    for child in node.children() {
+        load_node(&child, transform, buffers, images, meshes, skipped)?;
+    }
+
+    Ok(())
+}
+
+fn load_primitive(
+    primitive: &gltf::Primitive,
+    transform: Matrix4<f32>,
+    buffers: &[gltf::buffer::Data],
+    images: &[gltf::image::Data],
+) -> Result<Mesh> {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|b| &b.0[..]));
+
+    let Some(positions) = reader.read_positions() else {
+        bail!("Primitive {} has no positions", primitive.index());
+    };
+    let positions: Vec<f32> = positions
+        .flat_map(|p| {
+            let p: [f32; 3] = transform.transform_point(Point3::from(p)).into();
+            p
+        })
+        .collect();
+    let vertex_count = positions.len() as u32 / 3;
+
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertex_count).collect(),
+    };
+    if let Some(index) = indices.iter().find(|i| **i >= vertex_count) {
+        bail!("Primitive references vertex {index}, but it only has {vertex_count} of them");
+    }
+
+    let mut indices = triangle_list(primitive.mode(), indices);
+
+    // Mirroring transforms flip the winding of the triangles
+    if transform.determinant() < 0. {
+        indices.chunks_exact_mut(3).for_each(|tri| tri.swap(1, 2));
+    }
+
+    let normal_matrix = Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    )
+    .invert()
+    .unwrap_or_else(Matrix3::identity)
+    .transpose();
+
+    let (normals, normal_indices) = match reader.read_normals() {
+        Some(normals) => {
+            let normals = normals
+                .flat_map(|n| {
+                    let n: [f32; 3] = (normal_matrix * Vector3::from(n)).normalize().into();
+                    n
+                })
+                .collect::<Vec<_>>();
+            check_count("NORMAL", normals.len() / 3, vertex_count)?;
+            (normals, indices.clone())
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    // glTF has the origin of the texture coordinates in the top left corner, unlike OBJ
+    let (texcoords, texcoord_indices) = match reader.read_tex_coords(0) {
+        Some(texcoords) => {
+            let texcoords = texcoords
+                .into_f32()
+                .flat_map(|[u, v]| [u, 1. - v])
+                .collect::<Vec<_>>();
+            check_count("TEXCOORD_0", texcoords.len() / 2, vertex_count)?;
+            (texcoords, indices.clone())
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let mut mesh = Mesh::without_tangents(
+        positions,
+        indices,
+        normals,
+        normal_indices,
+        texcoords,
+        texcoord_indices,
+        load_material(&primitive.material(), images),
+    );
+
+    // Authored tangents match the normal maps they were baked with, the rest gets MikkTSpace.
+    // Tangents without normals have to be ignored according to the spec.
+    match reader.read_tangents() {
+        Some(tangents) if reader.read_normals().is_some() => {
+            let tangent_matrix = Matrix3::from_cols(
+                transform.x.truncate(),
+                transform.y.truncate(),
+                transform.z.truncate(),
+            );
+            let handedness = transform.determinant().signum();
+
+            mesh.tangents = tangents
+                .map(|[x, y, z, w]| {
+                    (tangent_matrix * Vector3::new(x, y, z))
+                        .normalize()
+                        .extend(w * handedness)
+                })
+                .collect();
+            check_count("TANGENT", mesh.tangents.len(), vertex_count)?;
+            mesh.tangent_indices = mesh.pos_indices.clone();
+        }
+        _ => mesh.generate_tangents(),
+    }
+
+    Ok(mesh)
+}
+
+/// The attributes are indexed together with the positions, so they need as many values
+fn check_count(attr: &str, count: usize, vertex_count: u32) -> Result<()> {
+    ensure!(
+        count == vertex_count as usize,
+        "Primitive has {count} {attr} values, but {vertex_count} positions"
+    );
+
+    Ok(())
+}
+
+/// Converts the indices of triangle strips and fans into a list of separate triangles
+fn triangle_list(mode: Mode, indices: Vec<u32>) -> Vec<u32> {
+    let triangles = indices.len().saturating_sub(2);
+
+    match mode {
+        // Every other triangle of a strip is reversed to keep the winding consistent
+        Mode::TriangleStrip => (0..triangles)
+            .flat_map(|i| {
+                let odd = i % 2;
+                [indices[i], indices[i + 1 + odd], indices[i + 2 - odd]]
+            })
+            .collect(),
+        Mode::TriangleFan => (0..triangles)
+            .flat_map(|i| [indices[i + 1], indices[i + 2], indices[0]])
+            .collect(),
+        _ => indices,
+    }
+}
+
+/// Loads the metallic-roughness model into [`Pbr`]
+/// and approximates it with the Blinn-Phong parameters of [`Material`]
+fn load_material(material: &gltf::Material, images: &[gltf::image::Data]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+
+    let texture = |texture: gltf::Texture, color_space| {
+        let mut tex = load_texture(images.get(texture.source().index())?, color_space);
+
+        let sampler = texture.sampler();
+        tex.wrap_u = wrap(sampler.wrap_s());
+        tex.wrap_v = wrap(sampler.wrap_t());
+
+        Some(tex)
+    };
+
+    let [r, g, b, alpha] = pbr.base_color_factor();
+    let base_color = Vector3::new(r, g, b);
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor();
+
+    // Dielectrics reflect about 4% of the light, metals reflect with their base color
+    let dielectric = Vector3::new(0.04, 0.04, 0.04);
+    let specular = dielectric * (1. - metallic) + base_color * metallic;
+
+    // Blinn-Phong exponent matching the GGX distribution with alpha = roughness^2
+    let alpha_sq = roughness.powi(4).max(1e-4);
+    let shininess = (2. / alpha_sq - 2.).max(1.);
+
+    // There is a single filter per material, so the sampler of the base color texture decides
+    let min_filter = pbr
+        .base_color_texture()
+        .and_then(|t| t.texture().sampler().min_filter());
+    let filter = match min_filter {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => Filter::Nearest,
+        Some(MinFilter::Linear | MinFilter::LinearMipmapNearest) => Filter::Bilinear,
+        _ => Filter::Trilinear,
+    };
+
+    let (alpha_cutoff, blend) = match material.alpha_mode() {
+        AlphaMode::Opaque => (None, Blend::Opaque),
+        AlphaMode::Mask => (Some(material.alpha_cutoff().unwrap_or(0.5)), Blend::Opaque),
+        AlphaMode::Blend => (None, Blend::Over),
+    };
+
+    Material {
+        ambient: base_color,
+        diffuse: base_color * (1. - metallic),
+        specular,
+        shininess,
+        dissolve: alpha,
+        alpha_cutoff,
+        blend,
+        filter,
+        double_sided: material.double_sided(),
+        ambient_texture: None,
+        diffuse_texture: pbr
+            .base_color_texture()
+            .and_then(|t| texture(t.texture(), ColorSpace::Srgb))
+            .unwrap_or_default(),
+        specular_texture: None,
+        normal_texture: material
+            .normal_texture()
+            .and_then(|t| texture(t.texture(), ColorSpace::Linear)),
+        shininess_texture: None,
+        dissolve_texture: None,
+        pbr: Pbr {
+            base_color,
+            metallic,
+            roughness,
+            occlusion_strength: material.occlusion_texture().map_or(1., |t| t.strength()),
+            emissive: material.emissive_factor().into(),
+            metallic_roughness_texture: pbr
+                .metallic_roughness_texture()
+                .and_then(|t| texture(t.texture(), ColorSpace::Linear)),
+            occlusion_texture: material
+                .occlusion_texture()
+                .and_then(|t| texture(t.texture(), ColorSpace::Linear)),
+            emissive_texture: material
+                .emissive_texture()
+                .and_then(|t| texture(t.texture(), ColorSpace::Srgb)),
+        },
+    }
+}
+
+fn wrap(mode: WrappingMode) -> Wrap {
+    match mode {
+        WrappingMode::ClampToEdge => Wrap::ClampToEdge,
+        WrappingMode::MirroredRepeat => Wrap::MirroredRepeat,
+        WrappingMode::Repeat => Wrap::Repeat,
+    }
+}
+
+fn load_texture(image: &gltf::image::Data, color_space: ColorSpace) -> Texture {
+    let pixels = &image.pixels;
+
+    let to_u8 = |c: f32| (c.clamp(0., 1.) * 255.).round() as u8;
+    let u16_at = |i: usize| u16::from_le_bytes([pixels[i], pixels[i + 1]]);
+    let f32_at = |i: usize| f32::from_le_bytes(pixels[i..i + 4].try_into().unwrap());
+
+    let pixel_count = (image.width * image.height) as usize;
+    let texel = |i: usize| match image.format {
+        Format::R8 => Vector4::new(pixels[i], pixels[i], pixels[i], 255),
+        Format::R8G8 => Vector4::new(pixels[2 * i], pixels[2 * i + 1], 0, 255),
+        Format::R8G8B8 => Vector4::new(pixels[3 * i], pixels[3 * i + 1], pixels[3 * i + 2], 255),
+        Format::R8G8B8A8 => Vector4::new(
+            pixels[4 * i],
+            pixels[4 * i + 1],
+            pixels[4 * i + 2],
+            pixels[4 * i + 3],
+        ),
+        // Only the high byte of 16-bit channels is kept
+        Format::R16 => {
+            let r = (u16_at(2 * i) >> 8) as u8;
+            Vector4::new(r, r, r, 255)
+        }
+        Format::R16G16 => Vector4::new(
+            (u16_at(4 * i) >> 8) as u8,
+            (u16_at(4 * i + 2) >> 8) as u8,
+            0,
+            255,
+        ),
+        Format::R16G16B16 => Vector4::new(
+            (u16_at(6 * i) >> 8) as u8,
+            (u16_at(6 * i + 2) >> 8) as u8,
+            (u16_at(6 * i + 4) >> 8) as u8,
+            255,
+        ),
+        Format::R16G16B16A16 => Vector4::new(
+            (u16_at(8 * i) >> 8) as u8,
+            (u16_at(8 * i + 2) >> 8) as u8,
+            (u16_at(8 * i + 4) >> 8) as u8,
+            (u16_at(8 * i + 6) >> 8) as u8,
+        ),
+        Format::R32G32B32FLOAT => Vector4::new(
+            to_u8(f32_at(12 * i)),
+            to_u8(f32_at(12 * i + 4)),
+            to_u8(f32_at(12 * i + 8)),
+            255,
+        ),
+        Format::R32G32B32A32FLOAT => Vector4::new(
+            to_u8(f32_at(16 * i)),
+            to_u8(f32_at(16 * i + 4)),
+            to_u8(f32_at(16 * i + 8)),
+            to_u8(f32_at(16 * i + 12)),
+        ),
+    };
+
+    Texture::new(
+        (0..pixel_count).map(texel).collect(),
+        image.width,
+        image.height,
+        color_space,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a single triangle with `normals` normals drawn with the glTF primitive `mode`,
+    /// the name keeps the files of tests apart
+    fn triangle_gltf(name: &str, normals: usize, mode: u32) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusterizer-{name}"));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Positions followed by the normals
+        let mut values = vec![[0., 0., 0.], [1., 0., 0.], [0., 1., 0.]];
+        values.resize(3 + normals, [0., 0., 1.]);
+        let buffer: Vec<u8> = values
+            .iter()
+            .flatten()
+            .flat_map(|c: &f32| c.to_le_bytes())
+            .collect();
+        std::fs::write(dir.join("triangle.bin"), &buffer).unwrap();
+
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [{{ "mesh": 0 }}],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0, "NORMAL": 1 }}, "mode": {mode} }}] }}],
+                "buffers": [{{ "uri": "triangle.bin", "byteLength": {len} }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": {normals_len} }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                       "min": [0, 0, 0], "max": [1, 1, 0] }},
+                    {{ "bufferView": 1, "componentType": 5126, "count": {normals}, "type": "VEC3" }}
+                ]
+            }}"#,
+            len = buffer.len(),
+            normals_len = normals * 12,
+        );
+        let path = dir.join("triangle.gltf");
+        std::fs::write(&path, json).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn attribute_counts_match_positions() {
+        let (solid, skipped) = load_gltf(triangle_gltf("normals-ok", 3, 4)).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(solid.meshes[0].normals.len(), 3);
+
+        let Err(err) = load_gltf(triangle_gltf("normals-short", 1, 4)) else {
+            panic!("The short NORMAL accessor wasn't rejected");
+        };
+        assert!(format!("{err:?}").contains("1 NORMAL values, but 3 positions"));
+    }
+
+    #[test]
+    fn points_are_skipped() {
+        let (solid, skipped) = load_gltf(triangle_gltf("points", 3, 0)).unwrap();
+
+        assert!(solid.meshes.is_empty());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].mode, Mode::Points);
+    }
+
+    #[test]
+    fn strips_alternate_winding() {
+        let strip = triangle_list(Mode::TriangleStrip, vec![0, 1, 2, 3, 4]);
+        assert_eq!(strip, [0, 1, 2, 1, 3, 2, 2, 3, 4]);
+
+        assert!(triangle_list(Mode::TriangleStrip, vec![0, 1]).is_empty());
+    }
+
+    #[test]
+    fn fans_keep_winding() {
+        let fan = triangle_list(Mode::TriangleFan, vec![0, 1, 2, 3]);
+        assert_eq!(fan, [1, 2, 0, 2, 3, 0]);
+
+        let list = triangle_list(Mode::Triangles, vec![0, 1, 2]);
+        assert_eq!(list, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,31 @@
+//! A software rasterizer.
+//!
+//! Load a [`Solid`] with [`load_solid`] or [`load_gltf`], place its instances in a [`Scene`],
+//! then render the scene with a [`Renderer`] into its [`Raster`].
+//! The finished frame can be read from [`Raster::img_buf`] or written to disk with the [`output`] module.
+
+#![feature(array_chunks)]
+#![feature(portable_simd)]
+
+pub mod camera;
+pub mod gltf;
+pub mod light;
+pub mod obj;
+pub mod output;
+pub mod raster;
+pub mod renderer;
+pub mod scene;
+pub mod shader;
+pub mod shadow;
+pub mod solid;
+
+pub use crate::gltf::{load_gltf, SkippedPrimitive};
+pub use camera::Camera;
+pub use light::Light;
+pub use obj::{load_solid, load_solid_with_tex_dir};
+pub use raster::{Blend, DownsampleFilter, Msaa, Raster};
+pub use renderer::{CullMode, FrontFace, Renderer};
+pub use scene::{Node, NodeId, Scene, SolidId, Transform};
+pub use shader::{FragmentShader, VertexShader};
+pub use shadow::ShadowSettings;
+pub use solid::{ColorSpace, Filter, Material, Mesh, Pbr, Solid, Texture, Wrap};
@@ -0,0 +1,136 @@
+use cgmath::{InnerSpace, Matrix4, Point3, Rad, Transform, Vector3};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Light {
+    /// Infinitely distant light, like the sun
+    Directional {
+        /// Direction in which the light travels
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        /// Rendered into a shadow map when the renderer has shadows enabled
+        casts_shadows: bool,
+    },
+    Point {
+        position: Point3<f32>,
+        color: Vector3<f32>,
+        /// Distance at which the intensity falls to zero
+        range: f32,
+        /// Rendered into a shadow cube map, which takes six passes
+        casts_shadows: bool,
+    },
+    /// Point light shining into a cone
+    Spot {
+        position: Point3<f32>,
+        /// Axis of the cone
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        range: f32,
+        /// Angle between the axis and the edge of the fully lit part of the cone
+        inner_angle: Rad<f32>,
+        /// Angle between the axis and the edge of the cone
+        outer_angle: Rad<f32>,
+        casts_shadows: bool,
+    },
+}
+
+impl Light {
+    /// Returns the light transformed by `mat`, used to move the light into view space
+    pub fn transform(&self, mat: Matrix4<f32>) -> Self {
+        match *self {
+            Light::Directional {
+                direction,
+                color,
+                casts_shadows,
+            } => Light::Directional {
+                direction: mat.transform_vector(direction).normalize(),
+                color,
+                casts_shadows,
+            },
+            Light::Point {
+                position,
+                color,
+                range,
+                casts_shadows,
+            } => Light::Point {
+                position: mat.transform_point(position),
+                color,
+                range,
+                casts_shadows,
+            },
+            Light::Spot {
+                position,
+                direction,
+                color,
+                range,
+                inner_angle,
+                outer_angle,
+                casts_shadows,
+            } => Light::Spot {
+                position: mat.transform_point(position),
+                direction: mat.transform_vector(direction).normalize(),
+                color,
+                range,
+                inner_angle,
+                outer_angle,
+                casts_shadows,
+            },
+        }
+    }
+
+    pub fn casts_shadows(&self) -> bool {
+        match *self {
+            Light::Directional { casts_shadows, .. }
+            | Light::Point { casts_shadows, .. }
+            | Light::Spot { casts_shadows, .. } => casts_shadows,
+        }
+    }
+
+    /// Returns the normalized direction from `pos` towards the light and the incoming radiance
+    pub fn incidence(&self, pos: Point3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        match *self {
+            Light::Directional {
+                direction, color, ..
+            } => (-direction, color),
+            Light::Point {
+                position,
+                color,
+                range,
+                ..
+            } => {
+                let (to_light, dist) = Self::to_light(position, pos);
+                (to_light, color * Self::falloff(dist, range))
+            }
+            Light::Spot {
+                position,
+                direction,
+                color,
+                range,
+                inner_angle,
+                outer_angle,
+                ..
+            } => {
+                let (to_light, dist) = Self::to_light(position, pos);
+
+                // Smooth transition between the cosines of the inner and the outer angle
+                let (cos_inner, cos_outer) = (inner_angle.0.cos(), outer_angle.0.cos());
+                let t = (-to_light.dot(direction) - cos_outer) / (cos_inner - cos_outer).max(1e-4);
+                let t = t.clamp(0., 1.);
+                let cone = t * t * (3. - 2. * t);
+
+                (to_light, color * Self::falloff(dist, range) * cone)
+            }
+        }
+    }
+
+    fn to_light(position: Point3<f32>, pos: Point3<f32>) -> (Vector3<f32>, f32) {
+        let to_light = position - pos;
+        let dist = to_light.magnitude();
+
+        (to_light / dist, dist)
+    }
+
+    /// Smooth window, so the light doesn't end abruptly at its range
+    fn falloff(dist: f32, range: f32) -> f32 {
+        (1. - (dist / range).powi(2)).max(0.).powi(2)
+    }
+}
@@ -1,42 +1,48 @@
-#![feature(array_chunks)]
-#![feature(portable_simd)]
-
 use std::time::{Duration, Instant};
 
-use camera::Camera;
-use cgmath::Point3;
-use eyre::Result;
-use raster::Raster;
-use renderer::Renderer;
+use cgmath::{Deg, Point3, Quaternion, Rotation3};
+use eyre::{eyre, Result};
+use rusterizer::{output, Camera, Filter, Raster, Renderer, Scene, Transform};
 use softbuffer::GraphicsContext;
 use winit::{
     dpi::{PhysicalPosition, PhysicalSize},
     event::{Event, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Window, WindowBuilder},
 };
 
-mod camera;
-mod obj;
-mod raster;
-mod renderer;
-mod solid;
-
+/// Initial resolution, the window can be resized afterwards
 const WIDTH: usize = 3840;
 const HEIGHT: usize = 2160;
 
 fn main() -> Result<()> {
-    let portal = obj::load_solid(
+    let mut portal = rusterizer::load_solid_with_tex_dir(
         "resources/portal/Portal_C/Portal_C.obj",
         "resources/portal/textures/",
     )?;
 
-    let scene = portal;
+    // The long corridors of the model are mostly seen at grazing angles
+    for mesh in &mut portal.meshes {
+        mesh.material.filter = Filter::Anisotropic { max_anisotropy: 8 };
+    }
+
+    let mut scene = Scene::new();
+    let portal = scene.add_solid(portal);
+    let transform = Transform {
+        rotation: Quaternion::from_angle_y(Deg(270.)),
+        ..Default::default()
+    };
+    scene.add_node(transform, Some(portal), None);
 
     let raster = Raster::new(WIDTH, HEIGHT);
     let camera = Camera::new(Point3::new(0., 20., 4.), 0.5, 0.002);
     let mut renderer = Renderer::new(raster, camera);
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--headless") {
+        return render_headless(&mut renderer, &scene, &args[1..]);
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop)?;
     window.set_inner_size(PhysicalSize::new(WIDTH as f32, HEIGHT as f32));
@@ -54,9 +60,27 @@ fn main() -> Result<()> {
 
         match event {
             Event::RedrawRequested(window_id) if window_id == graphics_context.window().id() => {
-                renderer.render_solid(&scene);
+                let start = Instant::now();
+                renderer.render_scene(&scene);
+
+                let elapsed = start.elapsed();
+                if elapsed > Duration::from_millis(16) {
+                    println!("{elapsed:?}");
+                }
+
                 let buffer = renderer.img_buf();
-                graphics_context.set_buffer(&buffer, WIDTH as u16, HEIGHT as u16);
+                let raster = renderer.raster();
+                graphics_context.set_buffer(buffer, raster.width() as u16, raster.height() as u16);
+            }
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                window_id,
+            } if window_id == graphics_context.window().id()
+                && size.width > 0
+                && size.height > 0 =>
+            {
+                renderer.resize(size.width as usize, size.height as usize);
+                graphics_context.window().request_redraw();
             }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
@@ -109,15 +133,15 @@ fn main() -> Result<()> {
                     return;
                 }
 
-                renderer.camera().adjust_look(position.x, position.y);
+                let center = window_center(graphics_context.window());
+                renderer
+                    .camera()
+                    .adjust_look(center.x - position.x, center.y - position.y);
                 graphics_context.window().request_redraw();
 
                 graphics_context
                     .window()
-                    .set_cursor_position(PhysicalPosition::new(
-                        WIDTH as f32 / 2.,
-                        HEIGHT as f32 / 2.,
-                    ))
+                    .set_cursor_position(center)
                     .unwrap();
                 just_moved = true;
             }
@@ -130,15 +154,36 @@ fn main() -> Result<()> {
                     last_mouse_sample = Instant::now();
                 }
 
+                let center = window_center(graphics_context.window());
                 graphics_context
                     .window()
-                    .set_cursor_position(PhysicalPosition::new(
-                        WIDTH as f32 / 2.,
-                        HEIGHT as f32 / 2.,
-                    ))
+                    .set_cursor_position(center)
                     .unwrap();
             }
             _ => {}
         }
     });
 }
+
+fn window_center(window: &Window) -> PhysicalPosition<f64> {
+    let size = window.inner_size();
+    PhysicalPosition::new(size.width as f64 / 2., size.height as f64 / 2.)
+}
+
+/// Renders a single frame without opening a window.
+///
+/// Usage: `rusterizer --headless <color output> [depth output]`
+fn render_headless(renderer: &mut Renderer, scene: &Scene, args: &[String]) -> Result<()> {
+    let color_path = args
+        .first()
+        .ok_or_else(|| eyre!("Usage: rusterizer --headless <color output> [depth output]"))?;
+
+    renderer.render_scene(scene);
+
+    output::save_color(renderer.raster(), color_path)?;
+    if let Some(depth_path) = args.get(1) {
+        output::save_depth(renderer.raster(), depth_path)?;
+    }
+
+    Ok(())
+}
@@ -1,28 +1,52 @@
-use eyre::Result;
+use std::path::Path;
 
-use crate::solid::{Material, Mesh, Solid};
+use cgmath::Vector3;
+use eyre::{bail, ensure, Result, WrapErr};
 
-pub fn load_solid(obj_path: &str, tex_dir: &str) -> Result<Solid> {
-    let mut load_options = tobj::LoadOptions::default();
-    load_options.triangulate = true;
+use crate::{
+    raster::Blend,
+    solid::{ColorSpace, Filter, Material, Mesh, Pbr, Solid, Texture, Wrap},
+};
 
-    let (models, materials) = tobj::load_obj(&obj_path, &load_options)?;
-    let materials = materials?;
+/// Loads an OBJ file, texture paths from the MTL file are resolved relative to the OBJ file
+pub fn load_solid<P: AsRef<Path>>(obj_path: P) -> Result<Solid> {
+    let obj_path = obj_path.as_ref();
+    let tex_dir = obj_path.parent().unwrap_or_else(|| Path::new(""));
+
+    load_solid_with_tex_dir(obj_path, tex_dir)
+}
+
+/// Loads an OBJ file, texture paths from the MTL file are resolved relative to `tex_dir`
+pub fn load_solid_with_tex_dir<P: AsRef<Path>, T: AsRef<Path>>(
+    obj_path: P,
+    tex_dir: T,
+) -> Result<Solid> {
+    let load_options = tobj::LoadOptions {
+        triangulate: true,
+        ..Default::default()
+    };
+
+    let obj_path = obj_path.as_ref();
+    let (models, materials) = tobj::load_obj(obj_path, &load_options)
+        .wrap_err_with(|| format!("Couldn't load '{}'", obj_path.display()))?;
+    let materials = materials
+        .wrap_err_with(|| format!("Couldn't load the materials of '{}'", obj_path.display()))?;
 
     let mut meshes = Vec::new();
     for model in models {
-        // TODO: modely bet materiálů...
-        let mat_index = model.mesh.material_id.unwrap();
-        let material = &materials[mat_index];
-        let diffuse_texture_path = format!("{}{}", tex_dir, material.diffuse_texture);
-
-        let diffuse_texture = if material.diffuse_texture == "" {
-            image::DynamicImage::new_rgb8(1, 1)
-        } else {
-            image::open(diffuse_texture_path)?
-        };
+        check_indices(&model)?;
 
-        let material = Material::new(diffuse_texture);
+        let material = match model.mesh.material_id {
+            Some(mat_index) => {
+                let Some(material) = materials.get(mat_index) else {
+                    bail!("Model '{}' references a missing material", model.name);
+                };
+
+                load_material(material, tex_dir.as_ref())
+                    .wrap_err_with(|| format!("Couldn't load material '{}'", material.name))?
+            }
+            None => Material::default(),
+        };
 
         let mesh = Mesh::new(
             model.mesh.positions,
@@ -39,3 +63,155 @@ pub fn load_solid(obj_path: &str, tex_dir: &str) -> Result<Solid> {
 
     Ok(Solid::new(meshes))
 }
+
+fn load_material(material: &tobj::Material, tex_dir: &Path) -> Result<Material> {
+    let load_texture = |name: &str, color_space| -> Result<Option<Texture>> {
+        match texture_path(name) {
+            Some(path) => {
+                let path = tex_dir.join(path);
+                let img = image::open(&path)
+                    .wrap_err_with(|| format!("Couldn't load texture '{}'", path.display()))?;
+
+                let mut texture = Texture::from_image(img, color_space);
+                texture.wrap_u = texture_wrap(name);
+                texture.wrap_v = texture_wrap(name);
+
+                Ok(Some(texture))
+            }
+            None => Ok(None),
+        }
+    };
+
+    let dissolve_texture = load_texture(&material.dissolve_texture, ColorSpace::Linear)?;
+    let diffuse_texture =
+        load_texture(&material.diffuse_texture, ColorSpace::Srgb)?.unwrap_or_default();
+
+    // MTL has no explicit blending, so only the dissolve makes the material blended.
+    // The alpha of diffuse textures usually cuts out leaves or decals with soft edges,
+    // alpha testing keeps them writing depth and casting shadows.
+    let (alpha_cutoff, blend) = if material.dissolve < 1. || dissolve_texture.is_some() {
+        (None, Blend::Over)
+    } else if diffuse_texture.alphas().any(|a| a < 255) {
+        (Some(0.5), Blend::Opaque)
+    } else {
+        (None, Blend::Opaque)
+    };
+
+    // Statements of the PBR extension of MTL, tobj keeps them as unknown parameters
+    let param = |key: &str| material.unknown_param.get(key).map(String::as_str);
+    let floats = |key: &str| -> Result<Option<Vec<f32>>> {
+        param(key)
+            .map(|value| value.split_whitespace().map(str::parse).collect())
+            .transpose()
+            .wrap_err_with(|| format!("Couldn't parse '{key}'"))
+    };
+
+    let metallic = floats("Pm")?.and_then(|v| v.first().copied());
+    let roughness = floats("Pr")?.and_then(|v| v.first().copied());
+    let emissive = match floats("Ke")?.as_deref() {
+        Some([r, g, b]) => Vector3::new(*r, *g, *b),
+        Some([k]) => Vector3::new(*k, *k, *k),
+        _ => Vector3::new(0., 0., 0.),
+    };
+
+    // Inverse of the mapping from roughness to the Blinn-Phong exponent used by the glTF loader
+    let roughness =
+        roughness.unwrap_or_else(|| (2. / (material.shininess.max(0.) + 2.)).powf(0.25));
+
+    // tobj only recognizes bump, the PBR extension names normal maps norm
+    let normal_texture = match param("norm") {
+        Some(norm) if material.normal_texture.is_empty() => norm,
+        _ => &material.normal_texture,
+    };
+
+    let pbr = Pbr {
+        base_color: material.diffuse.into(),
+        metallic: metallic.unwrap_or(0.),
+        roughness,
+        occlusion_strength: 1.,
+        emissive,
+        metallic_roughness_texture: None,
+        occlusion_texture: None,
+        emissive_texture: load_texture(param("map_Ke").unwrap_or(""), ColorSpace::Srgb)?,
+    };
+
+    Ok(Material {
+        ambient: material.ambient.into(),
+        diffuse: material.diffuse.into(),
+        specular: material.specular.into(),
+        shininess: material.shininess,
+        dissolve: material.dissolve,
+        alpha_cutoff,
+        blend,
+        filter: Filter::Trilinear,
+        double_sided: false,
+        ambient_texture: load_texture(&material.ambient_texture, ColorSpace::Srgb)?,
+        diffuse_texture,
+        specular_texture: load_texture(&material.specular_texture, ColorSpace::Srgb)?,
+        normal_texture: load_texture(normal_texture, ColorSpace::Linear)?,
+        shininess_texture: load_texture(&material.shininess_texture, ColorSpace::Linear)?,
+        dissolve_texture,
+        pbr,
+    })
+}
+
+/// tobj doesn't check that the indices point into the vertex arrays
+fn check_indices(model: &tobj::Model) -> Result<()> {
+    let mesh = &model.mesh;
+
+    let check = |indices: &[u32], values: &[f32], dim: usize, attr: &str| -> Result<()> {
+        ensure!(
+            indices.is_empty() || indices.len() == mesh.indices.len(),
+            "Model '{}' has {} {attr} indices, but {} position indices",
+            model.name,
+            indices.len(),
+            mesh.indices.len()
+        );
+
+        let count = values.len() / dim;
+        if let Some(index) = indices.iter().find(|i| **i as usize >= count) {
+            bail!(
+                "Model '{}' references {attr} {index}, but it only has {count} of them",
+                model.name
+            );
+        }
+
+        Ok(())
+    };
+
+    check(&mesh.indices, &mesh.positions, 3, "position")?;
+    check(&mesh.normal_indices, &mesh.normals, 3, "normal")?;
+    check(&mesh.texcoord_indices, &mesh.texcoords, 2, "texcoord")?;
+
+    Ok(())
+}
+
+/// Extracts the file name from a texture statement of the MTL file,
+/// which can be preceded by options like `-bm 0.5`
+fn texture_path(statement: &str) -> Option<String> {
+    let statement = statement.trim();
+    if statement.is_empty() {
+        return None;
+    }
+
+    let path = if statement.starts_with('-') {
+        statement.split_whitespace().last()?
+    } else {
+        statement
+    };
+
+    // Exporters on Windows tend to write backslashes
+    Some(path.replace('\\', "/"))
+}
+
+/// MTL textures repeat, unless the texture statement contains `-clamp on`
+fn texture_wrap(statement: &str) -> Wrap {
+    let mut tokens = statement.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if token == "-clamp" && tokens.next() == Some("on") {
+            return Wrap::ClampToEdge;
+        }
+    }
+
+    Wrap::Repeat
+}
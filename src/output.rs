@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use eyre::Result;
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb, RgbImage};
+
+use crate::raster::Raster;
+
+/// Writes the color buffer of the raster to `path`, the format is deduced from the extension
+pub fn save_color<P: AsRef<Path>>(raster: &Raster, path: P) -> Result<()> {
+    let pixels = raster
+        .img_buf()
+        .iter()
+        .flat_map(|col| {
+            let [_, r, g, b] = col.to_be_bytes();
+            [r, g, b]
+        })
+        .collect();
+
+    let img = RgbImage::from_raw(raster.width() as u32, raster.height() as u32, pixels)
+        .expect("Raster buffer doesn't match its dimensions");
+    img.save(path)?;
+
+    Ok(())
+}
+
+/// Writes the depth buffer of the raster to `path`.
+///
+/// EXR files get the raw NDC depth values, other formats are quantized to 16-bit grayscale.
+pub fn save_depth<P: AsRef<Path>>(raster: &Raster, path: P) -> Result<()> {
+    let path = path.as_ref();
+
+    let depths = raster.z_buf().iter().flat_map(|z| [*z, *z, *z]).collect();
+    let img: ImageBuffer<Rgb<f32>, Vec<f32>> =
+        ImageBuffer::from_raw(raster.width() as u32, raster.height() as u32, depths)
+            .expect("Raster buffer doesn't match its dimensions");
+    let img = DynamicImage::ImageRgb32F(img);
+
+    match ImageFormat::from_path(path)? {
+        ImageFormat::OpenExr => img.save(path)?,
+        _ => img.into_luma16().save(path)?,
+    }
+
+    Ok(())
+}
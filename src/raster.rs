@@ -1,7 +1,209 @@
+use std::{
+    simd::{cmp::SimdPartialOrd, f32x8, mask32x8, u32x8, usizex8, Mask},
+    sync::LazyLock,
+};
+
+use cgmath::{ElementWise, Vector3, Vector4};
+use rayon::prelude::*;
+
+/// How a fragment is combined with the color stored in the raster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blend {
+    /// Overwrites the color and the depth, alpha is ignored
+    Opaque,
+    /// Classic transparency, `src * alpha + dst * (1 - alpha)`
+    Over,
+    /// `dst + src * alpha`, for glows and particles
+    Additive,
+    /// Tints the raster, `dst * (src * alpha + 1 - alpha)`
+    Multiply,
+}
+
+impl Blend {
+    /// Combines the RGBA fragment color `src` with the color `dst` of the raster
+    pub fn apply(self, src: Vector4<f32>, dst: Vector3<f32>) -> Vector3<f32> {
+        let alpha = src.w;
+        let src = src.truncate();
+
+        match self {
+            Blend::Opaque => src,
+            Blend::Over => src * alpha + dst * (1. - alpha),
+            Blend::Additive => dst + src * alpha,
+            Blend::Multiply => {
+                dst.mul_element_wise(src * alpha + Vector3::new(1., 1., 1.) * (1. - alpha))
+            }
+        }
+    }
+}
+
+/// Converts a linear color in the [0, 1] range to sRGB encoded 0RGB
+pub(crate) fn pack_color(col: Vector3<f32>) -> u32 {
+    let col = col.map(encode_srgb);
+    u32::from_be_bytes([0, col.x, col.y, col.z])
+}
+
+/// Converts a sRGB encoded 0RGB color to a linear color in the [0, 1] range
+pub(crate) fn unpack_color(col: u32) -> Vector3<f32> {
+    let [_, r, g, b] = col.to_be_bytes();
+    Vector3::new(r, g, b).map(decode_srgb)
+}
+
+/// Applies the sRGB transfer function to a linear value in the [0, 1] range
+pub(crate) fn encode_srgb(c: f32) -> u8 {
+    let c = c.clamp(0., 1.);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    };
+
+    (c * 255.).round() as u8
+}
+
+/// Inverse of [`encode_srgb`], the results are cached for all 256 values
+pub(crate) fn decode_srgb(c: u8) -> f32 {
+    static LINEAR: LazyLock<[f32; 256]> = LazyLock::new(|| {
+        std::array::from_fn(|c| {
+            let c = c as f32 / 255.;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
+
+    LINEAR[c as usize]
+}
+
+/// Marks the end of a fragment list
+const NO_FRAGMENT: u32 = u32::MAX;
+
+/// Sample positions are given in 1/`SAMPLE_GRID` of a pixel relative to the pixel center
+pub(crate) const SAMPLE_GRID: i32 = 16;
+
+pub(crate) const MAX_SAMPLES: usize = 8;
+
+/// Number of samples per pixel of the multisample anti-aliasing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msaa {
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Msaa {
+    pub fn samples(self) -> usize {
+        self.positions().len()
+    }
+
+    /// The standard sample patterns of Direct3D
+    pub fn positions(self) -> &'static [(i32, i32)] {
+        match self {
+            Msaa::Off => &[(0, 0)],
+            Msaa::X2 => &[(4, 4), (-4, -4)],
+            Msaa::X4 => &[(-2, -6), (6, -2), (-6, 2), (2, 6)],
+            Msaa::X8 => &[
+                (1, -3),
+                (-1, 3),
+                (5, 1),
+                (-3, -5),
+                (-5, 5),
+                (-7, -1),
+                (3, 7),
+                (7, -7),
+            ],
+        }
+    }
+}
+
+/// Reconstruction filter used when downsampling a supersampled raster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownsampleFilter {
+    /// Average of the samples of the pixel
+    Box,
+    /// Triangle filter with a radius of one pixel
+    Tent,
+    /// Windowed sinc with 3 lobes, the sharpest of the filters, but it can ring on hard edges
+    Lanczos,
+}
+
+impl DownsampleFilter {
+    /// Weight of a sample `t` pixels from the pixel center
+    fn weight(self, t: f32) -> f32 {
+        let t = t.abs();
+
+        match self {
+            DownsampleFilter::Box => {
+                if t < 0.5 {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            DownsampleFilter::Tent => (1. - t).max(0.),
+            DownsampleFilter::Lanczos => {
+                const LOBES: f32 = 3.;
+
+                let sinc = |x: f32| {
+                    if x == 0. {
+                        1.
+                    } else {
+                        let x = x * std::f32::consts::PI;
+                        x.sin() / x
+                    }
+                };
+
+                if t < LOBES {
+                    sinc(t) * sinc(t / LOBES)
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+
+    /// Radius of the filter in pixels
+    fn radius(self) -> f32 {
+        match self {
+            DownsampleFilter::Box => 0.5,
+            DownsampleFilter::Tent => 1.,
+            DownsampleFilter::Lanczos => 3.,
+        }
+    }
+
+    /// Offsets of the source pixels relative to the first source pixel of the output pixel
+    /// and their normalized weights
+    fn taps(self, factor: usize) -> Vec<(isize, f32)> {
+        let factor = factor as f32;
+        let reach = (self.radius() * factor).ceil() as isize;
+
+        let mut taps: Vec<_> = (-reach..factor as isize + reach)
+            .map(|k| {
+                let t = (k as f32 + 0.5 - factor / 2.) / factor;
+                (k, self.weight(t))
+            })
+            .filter(|(_, w)| *w != 0.)
+            .collect();
+
+        let sum: f32 = taps.iter().map(|(_, w)| w).sum();
+        taps.iter_mut().for_each(|(_, w)| *w /= sum);
+
+        taps
+    }
+}
+
 pub struct Raster {
     /// Buffer of 0RGB values
     img_buf: Vec<u32>,
     z_buf: Vec<f32>,
+    /// Color and depth of every sample with MSAA, `img_buf` and `z_buf` hold the resolved values
+    sample_buf: Vec<u32>,
+    sample_z_buf: Vec<f32>,
+    msaa: Msaa,
+    /// A-buffer of the order-independent transparency, `None` if it is disabled
+    fragment_lists: Option<FragmentLists>,
 
     width: usize,
     height: usize,
@@ -12,11 +214,55 @@ impl Raster {
         Self {
             img_buf: vec![0; width * height],
             z_buf: vec![1.0; width * height],
+            sample_buf: Vec::new(),
+            sample_z_buf: Vec::new(),
+            msaa: Msaa::Off,
+            fragment_lists: None,
             width,
             height,
         }
     }
 
+    /// Reallocates the buffers for the new resolution, keeps the MSAA and transparency modes
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let msaa = self.msaa;
+        let order_independent = self.order_independent_transparency();
+
+        *self = Self::new(width, height);
+        self.set_msaa(msaa);
+        self.set_order_independent_transparency(order_independent);
+    }
+
+    /// Triangles are rasterized with per-sample coverage and depth, but shaded once per pixel.
+    /// The samples are averaged into [`Raster::img_buf`] when a tile is finished.
+    pub fn set_msaa(&mut self, msaa: Msaa) {
+        let len = match msaa {
+            Msaa::Off => 0,
+            _ => self.width * self.height * msaa.samples(),
+        };
+
+        self.msaa = msaa;
+        self.sample_buf = vec![0; len];
+        self.sample_z_buf = vec![1.0; len];
+    }
+
+    pub fn msaa(&self) -> Msaa {
+        self.msaa
+    }
+
+    /// Blended fragments are collected into per-pixel lists and composited sorted by depth
+    /// when a tile is finished, instead of being blended in the order they are drawn
+    pub fn set_order_independent_transparency(&mut self, enabled: bool) {
+        self.fragment_lists = enabled.then(|| FragmentLists {
+            heads: vec![NO_FRAGMENT; self.width * self.height],
+            pools: Vec::new(),
+        });
+    }
+
+    pub fn order_independent_transparency(&self) -> bool {
+        self.fragment_lists.is_some()
+    }
+
     /// Currently panics if the pixel is out of bounds
     pub fn set_pixel(&mut self, x: usize, y: usize, col: u32, z: f32) {
         let index = self.index(x, y);
@@ -28,6 +274,13 @@ impl Raster {
         if self.z_buf[index] > z {
             self.z_buf[index] = z;
             self.img_buf[index] = col;
+
+            // Every sample is covered, so the pixel resolves to the same color
+            let samples = self.msaa.samples();
+            if self.msaa != Msaa::Off {
+                self.sample_buf[index * samples..(index + 1) * samples].fill(col);
+                self.sample_z_buf[index * samples..(index + 1) * samples].fill(z);
+            }
         }
     }
 
@@ -35,12 +288,397 @@ impl Raster {
         &self.img_buf
     }
 
+    pub fn z_buf(&self) -> &[f32] {
+        &self.z_buf
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn clear(&mut self) {
         self.img_buf.fill(0);
         self.z_buf.fill(1.0);
+        self.sample_buf.fill(0);
+        self.sample_z_buf.fill(1.0);
+
+        if let Some(lists) = &mut self.fragment_lists {
+            lists.heads.fill(NO_FRAGMENT);
+            lists.pools.iter_mut().for_each(Vec::clear);
+        }
+    }
+
+    /// Splits the raster into horizontal bands of `tile_height` rows,
+    /// which can be rendered into independently
+    pub(crate) fn tiles_mut(&mut self, tile_height: usize) -> Vec<Tile<'_>> {
+        let chunk_len = tile_height * self.width;
+        let width = self.width;
+        let tile_count = self.height.div_ceil(tile_height);
+        let msaa = self.msaa;
+
+        // Every tile appends to its own pool, so the tiles can be rendered in parallel
+        let lists: Vec<_> = match &mut self.fragment_lists {
+            Some(lists) => {
+                lists.pools.resize_with(tile_count, Vec::new);
+                lists
+                    .heads
+                    .chunks_mut(chunk_len)
+                    .zip(&mut lists.pools)
+                    .map(|(heads, pool)| Some(TileLists { heads, pool }))
+                    .collect()
+            }
+            None => (0..tile_count).map(|_| None).collect(),
+        };
+
+        let samples: Vec<_> = match msaa {
+            Msaa::Off => (0..tile_count).map(|_| None).collect(),
+            _ => {
+                let sample_chunk_len = chunk_len * msaa.samples();
+                self.sample_buf
+                    .chunks_mut(sample_chunk_len)
+                    .zip(self.sample_z_buf.chunks_mut(sample_chunk_len))
+                    .map(Some)
+                    .collect()
+            }
+        };
+
+        self.img_buf
+            .chunks_mut(chunk_len)
+            .zip(self.z_buf.chunks_mut(chunk_len))
+            .zip(samples)
+            .zip(lists)
+            .enumerate()
+            .map(|(i, (((img_buf, z_buf), samples), lists))| {
+                let y_start = i * tile_height;
+                let y_end = y_start + img_buf.len() / width;
+
+                // Without MSAA the single sample of a pixel is the pixel itself
+                let (colors, depths, resolved) = match samples {
+                    Some((colors, depths)) => (colors, depths, Some((img_buf, z_buf))),
+                    None => (img_buf, z_buf, None),
+                };
+
+                Tile {
+                    colors,
+                    depths,
+                    resolved,
+                    msaa,
+                    lists,
+                    width,
+                    y_start,
+                    y_end,
+                }
+            })
+            .collect()
+    }
+
+    /// Fills the raster with `src` filtered down to its resolution,
+    /// `src` has to be exactly `factor` times larger in both dimensions
+    pub fn downsample_from(&mut self, src: &Raster, factor: usize, filter: DownsampleFilter) {
+        assert!(src.width == self.width * factor && src.height == self.height * factor);
+
+        let taps = filter.taps(factor);
+        let clamp = |i: isize, len: usize| i.clamp(0, len as isize - 1) as usize;
+
+        // The filter is separable, so the rows are filtered first and the columns second
+        let mut rows = vec![Vector3::new(0., 0., 0.); self.width * src.height];
+        rows.par_chunks_mut(self.width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let src_row = &src.img_buf[y * src.width..(y + 1) * src.width];
+                for (x, col) in row.iter_mut().enumerate() {
+                    let first = (x * factor) as isize;
+                    *col = taps.iter().fold(Vector3::new(0., 0., 0.), |sum, (k, w)| {
+                        sum + unpack_color(src_row[clamp(first + k, src.width)]) * *w
+                    });
+                }
+            });
+
+        let width = self.width;
+        self.img_buf
+            .par_chunks_mut(width)
+            .zip(self.z_buf.par_chunks_mut(width))
+            .enumerate()
+            .for_each(|(y, (img_row, z_row))| {
+                let first = (y * factor) as isize;
+                for x in 0..width {
+                    let col = taps.iter().fold(Vector3::new(0., 0., 0.), |sum, (k, w)| {
+                        sum + rows[clamp(first + k, src.height) * width + x] * *w
+                    });
+                    img_row[x] = pack_color(col);
+
+                    // Depth isn't filtered, the closest sample keeps the edges of the geometry
+                    z_row[x] = (0..factor)
+                        .flat_map(|sy| {
+                            let start = (y * factor + sy) * src.width + x * factor;
+                            &src.z_buf[start..start + factor]
+                        })
+                        .copied()
+                        .fold(f32::MAX, f32::min);
+                }
+            });
     }
 
     fn index(&self, x: usize, y: usize) -> usize {
         y * self.width + x
     }
 }
+
+/// Per-pixel linked lists of blended fragments
+struct FragmentLists {
+    /// Index of the last fragment of every pixel in the pool of its tile
+    heads: Vec<u32>,
+    pools: Vec<Vec<ListFragment>>,
+}
+
+/// Fragment lists of the pixels of a single tile
+struct TileLists<'r> {
+    heads: &'r mut [u32],
+    pool: &'r mut Vec<ListFragment>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ListFragment {
+    col: Vector4<f32>,
+    z: f32,
+    blend: Blend,
+    /// Samples of the pixel covered by the fragment
+    coverage: u8,
+    /// Previous fragment of the same pixel
+    next: u32,
+}
+
+/// A horizontal band of the [`Raster`], addressed with the coordinates of the whole raster.
+///
+/// Every pixel has one or more samples, depending on the MSAA mode of the raster.
+pub(crate) struct Tile<'r> {
+    /// Color and depth of every sample
+    colors: &'r mut [u32],
+    depths: &'r mut [f32],
+    /// Color and depth buffer the samples are averaged into, `None` without MSAA
+    resolved: Option<(&'r mut [u32], &'r mut [f32])>,
+    msaa: Msaa,
+    lists: Option<TileLists<'r>>,
+
+    width: usize,
+    y_start: usize,
+    y_end: usize,
+}
+
+impl<'r> Tile<'r> {
+    pub fn msaa(&self) -> Msaa {
+        self.msaa
+    }
+
+    /// Sample positions of every pixel, see [`SAMPLE_GRID`]
+    pub fn sample_positions(&self) -> &'static [(i32, i32)] {
+        self.msaa.positions()
+    }
+
+    /// Writes the sample if it passes the depth test
+    pub fn set_sample(&mut self, x: usize, y: usize, sample: usize, col: u32, z: f32) {
+        let Some(index) = self.sample_index(x, y, sample) else {
+            return;
+        };
+
+        if self.depths[index] > z {
+            self.depths[index] = z;
+            self.colors[index] = col;
+        }
+    }
+
+    /// Blends the color into the samples of the `coverage` mask, which must have passed
+    /// the depth test. The depth isn't written, so transparent surfaces don't hide
+    /// what is drawn behind them later.
+    ///
+    /// With order-independent transparency the fragment is only stored until [`Tile::resolve`].
+    pub fn blend_pixel(
+        &mut self,
+        x: usize,
+        y: usize,
+        coverage: u8,
+        col: Vector4<f32>,
+        z: f32,
+        blend: Blend,
+    ) {
+        let Some(first) = self.sample_index(x, y, 0) else {
+            return;
+        };
+
+        if let Some(lists) = &mut self.lists {
+            let pixel = first / self.msaa.samples();
+            lists.pool.push(ListFragment {
+                col,
+                z,
+                blend,
+                coverage,
+                next: lists.heads[pixel],
+            });
+            lists.heads[pixel] = (lists.pool.len() - 1) as u32;
+            return;
+        }
+
+        for sample in 0..self.msaa.samples() {
+            if coverage & (1 << sample) != 0 {
+                let dst = unpack_color(self.colors[first + sample]);
+                self.colors[first + sample] = pack_color(blend.apply(col, dst));
+            }
+        }
+    }
+
+    /// Finishes the tile, composites the fragment lists from back to front
+    /// and averages the samples of every pixel with MSAA
+    pub fn resolve(&mut self) {
+        self.resolve_fragment_lists();
+        self.resolve_samples();
+    }
+
+    fn resolve_fragment_lists(&mut self) {
+        let Some(lists) = &mut self.lists else {
+            return;
+        };
+
+        let samples = self.msaa.samples();
+        let mut fragments = Vec::new();
+        for (pixel, head) in lists.heads.iter_mut().enumerate() {
+            let mut next = *head;
+            while next != NO_FRAGMENT {
+                let fragment = lists.pool[next as usize];
+                fragments.push(fragment);
+                next = fragment.next;
+            }
+
+            if fragments.is_empty() {
+                continue;
+            }
+
+            // The lists are linked backwards, so that fragments of equal depth
+            // keep the order in which they were drawn after the stable sort
+            fragments.reverse();
+            fragments.sort_by(|a, b| b.z.total_cmp(&a.z));
+
+            for sample in 0..samples {
+                let index = pixel * samples + sample;
+
+                let mut col = unpack_color(self.colors[index]);
+                for fragment in &fragments {
+                    // Opaque fragments drawn after the transparent one could have covered it
+                    if fragment.coverage & (1 << sample) != 0 && fragment.z < self.depths[index] {
+                        col = fragment.blend.apply(fragment.col, col);
+                    }
+                }
+                self.colors[index] = pack_color(col);
+            }
+
+            *head = NO_FRAGMENT;
+            fragments.clear();
+        }
+
+        lists.pool.clear();
+    }
+
+    fn resolve_samples(&mut self) {
+        let Some((img_buf, z_buf)) = &mut self.resolved else {
+            return;
+        };
+
+        let samples = self.msaa.samples();
+        for (pixel, (col, z)) in img_buf.iter_mut().zip(z_buf.iter_mut()).enumerate() {
+            let range = pixel * samples..(pixel + 1) * samples;
+
+            let sum = self.colors[range.clone()]
+                .iter()
+                .fold(Vector3::new(0., 0., 0.), |sum, c| sum + unpack_color(*c));
+            *col = pack_color(sum / samples as f32);
+
+            // The closest sample, so the depth buffer still covers the edges of the geometry
+            *z = self.depths[range].iter().copied().fold(f32::MAX, f32::min);
+        }
+    }
+
+    /// Returns true if a fragment with depth `z` is closer than the stored sample
+    pub fn depth_test(&self, x: usize, y: usize, sample: usize, z: f32) -> bool {
+        match self.sample_index(x, y, sample) {
+            Some(index) => self.depths[index] > z,
+            None => false,
+        }
+    }
+
+    /// Depth test of 8 consecutive pixels starting at `x`, returns the lanes of `mask` that passed.
+    ///
+    /// Only usable without MSAA.
+    pub fn depth_test_x8(&self, x: usize, y: usize, z: f32x8, mask: mask32x8) -> mask32x8 {
+        let (indices, enabled) = self.indices_x8(x, y, mask);
+        let old_z = f32x8::gather_select(self.depths, enabled, indices, f32x8::splat(f32::MIN));
+
+        mask & old_z.simd_gt(z)
+    }
+
+    /// Writes 8 consecutive pixels starting at `x` without a depth test,
+    /// lanes that aren't set in `mask` or that lie outside of the tile are skipped.
+    ///
+    /// Only usable without MSAA.
+    pub fn set_pixels_x8(&mut self, x: usize, y: usize, cols: u32x8, z: f32x8, mask: mask32x8) {
+        let (indices, enabled) = self.indices_x8(x, y, mask);
+
+        cols.scatter_select(self.colors, enabled, indices);
+        z.scatter_select(self.depths, enabled, indices);
+    }
+
+    fn indices_x8(&self, x: usize, y: usize, mask: mask32x8) -> (usizex8, Mask<isize, 8>) {
+        debug_assert_eq!(self.msaa, Msaa::Off);
+
+        let xs = usizex8::splat(x) + usizex8::from_array([0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let in_tile = y >= self.y_start && y < self.y_end;
+        let enabled = mask.cast() & xs.simd_lt(usizex8::splat(self.width)) & Mask::splat(in_tile);
+
+        let row = y.saturating_sub(self.y_start) * self.width;
+        (usizex8::splat(row) + xs, enabled)
+    }
+
+    fn sample_index(&self, x: usize, y: usize, sample: usize) -> Option<usize> {
+        if x >= self.width || y < self.y_start || y >= self.y_end {
+            return None;
+        }
+
+        let pixel = (y - self.y_start) * self.width + x;
+        Some(pixel * self.msaa.samples() + sample)
+    }
+
+    /// First row of the tile
+    pub fn y_start(&self) -> usize {
+        self.y_start
+    }
+
+    /// Row after the last row of the tile
+    pub fn y_end(&self) -> usize {
+        self.y_end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsample_taps_sum_to_one() {
+        for filter in [
+            DownsampleFilter::Box,
+            DownsampleFilter::Tent,
+            DownsampleFilter::Lanczos,
+        ] {
+            for factor in 1..=4 {
+                let sum: f32 = filter.taps(factor).iter().map(|(_, w)| w).sum();
+                assert!(
+                    (sum - 1.).abs() < 1e-5,
+                    "{filter:?} x{factor} sums to {sum}"
+                );
+            }
+        }
+    }
+}
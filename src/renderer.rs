@@ -1,89 +1,399 @@
 use std::{
     ops::{AddAssign, MulAssign},
-    simd::{f32x8, i32x8},
-    time::{Duration, Instant},
+    simd::{cmp::SimdPartialOrd, f32x8, i32x8, i64x8, num::SimdInt, u32x8},
+    sync::Arc,
 };
 
-use cgmath::{Deg, Matrix4, Vector2, Vector4};
+use cgmath::{Deg, InnerSpace, Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
+use rayon::prelude::*;
 
 use crate::{
     camera::Camera,
-    raster::Raster,
+    light::Light,
+    raster::{pack_color, Blend, DownsampleFilter, Msaa, Raster, Tile, MAX_SAMPLES, SAMPLE_GRID},
+    scene::Scene,
+    shader::{
+        Fragment, FragmentShader, TexturedShader, Uniforms, Varyings, VertexInput, VertexShader,
+        MAX_VARYINGS,
+    },
+    shadow::{self, ShadowFace, ShadowMap, ShadowSettings, ShadowShader},
     solid::{Material, Mesh, Solid},
-    HEIGHT, WIDTH,
 };
 
+/// Planes of the view frustum in clip space, a vertex lies inside of a plane
+/// if the dot product of the plane and the vertex position is non-negative
+const CLIP_PLANES: [Vector4<f32>; 6] = [
+    // near, far
+    Vector4::new(0., 0., 1., 1.),
+    Vector4::new(0., 0., -1., 1.),
+    // left, right
+    Vector4::new(1., 0., 0., 1.),
+    Vector4::new(-1., 0., 0., 1.),
+    // bottom, top
+    Vector4::new(0., 1., 0., 1.),
+    Vector4::new(0., -1., 0., 1.),
+];
+
+/// Number of fractional bits of the fixed-point screen coordinates
+const SUBPIXEL_BITS: u32 = 8;
+const SUBPIXEL_SCALE: i64 = 1 << SUBPIXEL_BITS;
+
+/// Height of the screen tiles in rows, the tiles span the whole width of the raster
+const TILE_HEIGHT: usize = 16;
+
+/// Which faces of triangles are discarded before rasterization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+/// Winding of the front faces of triangles as seen on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontFace {
+    Cw,
+    Ccw,
+}
+
 pub struct Renderer {
     raster: Raster,
     camera: Camera,
     persp: Matrix4<f32>,
+    parallel: bool,
+    simd: bool,
+    cull_mode: CullMode,
+    front_face: FrontFace,
+    lights: Vec<Light>,
+    ambient_light: Vector3<f32>,
+    supersampling: Option<Supersampling>,
+    shadows: Option<ShadowPass>,
+}
+
+/// Larger raster the frame is rendered into before it is downsampled into the output raster
+struct Supersampling {
+    raster: Raster,
+    factor: usize,
+    filter: DownsampleFilter,
+}
+
+/// Depth raster the shadow maps are rendered into, it is reused for all lights
+struct ShadowPass {
+    raster: Raster,
+    settings: ShadowSettings,
 }
 
 impl Renderer {
     pub fn new(raster: Raster, camera: Camera) -> Self {
+        let persp = Self::projection(raster.width(), raster.height());
+
         Self {
             raster,
             camera,
-            persp: cgmath::perspective(Deg(60.), WIDTH as f32 / HEIGHT as f32, 0.1, 50.),
+            persp,
+            parallel: true,
+            simd: false,
+            cull_mode: CullMode::None,
+            front_face: FrontFace::Ccw,
+            lights: Vec::new(),
+            ambient_light: Vector3::new(0.1, 0.1, 0.1),
+            supersampling: None,
+            shadows: None,
         }
     }
 
+    /// Reallocates the raster and updates the projection for the new resolution
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.raster.resize(width, height);
+        if let Some(ss) = &mut self.supersampling {
+            ss.raster.resize(width * ss.factor, height * ss.factor);
+        }
+        self.persp = Self::projection(width, height);
+    }
+
+    fn projection(width: usize, height: usize) -> Matrix4<f32> {
+        cgmath::perspective(Deg(60.), width as f32 / height as f32, 0.1, 50.)
+    }
+
     pub fn img_buf(&self) -> &[u32] {
         self.raster.img_buf()
     }
 
+    pub fn raster(&self) -> &Raster {
+        &self.raster
+    }
+
     pub fn camera(&mut self) -> &mut Camera {
         &mut self.camera
     }
 
+    /// Lights of the scene in world space
+    pub fn lights(&mut self) -> &mut Vec<Light> {
+        &mut self.lights
+    }
+
+    pub fn set_ambient_light(&mut self, ambient_light: Vector3<f32>) {
+        self.ambient_light = ambient_light;
+    }
+
+    /// Switches between rasterizing the screen tiles in parallel and on a single thread,
+    /// both produce identical images
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// Switches between the scalar and the 8-wide SIMD inner loop of the rasterizer,
+    /// both produce identical images
+    pub fn set_simd(&mut self, simd: bool) {
+        self.simd = simd;
+    }
+
+    /// See [`Raster::set_msaa`]
+    pub fn set_msaa(&mut self, msaa: Msaa) {
+        self.raster.set_msaa(msaa);
+        if let Some(ss) = &mut self.supersampling {
+            ss.raster.set_msaa(msaa);
+        }
+    }
+
+    /// See [`Raster::set_order_independent_transparency`]
+    pub fn set_order_independent_transparency(&mut self, enabled: bool) {
+        self.raster.set_order_independent_transparency(enabled);
+        if let Some(ss) = &mut self.supersampling {
+            ss.raster.set_order_independent_transparency(enabled);
+        }
+    }
+
+    /// Renders into a raster `factor` times larger in both dimensions
+    /// and downsamples it into the output raster with `filter`, a `factor` of 1 disables it
+    pub fn set_supersampling(&mut self, factor: usize, filter: DownsampleFilter) {
+        self.supersampling = (factor > 1).then(|| {
+            let mut raster =
+                Raster::new(self.raster.width() * factor, self.raster.height() * factor);
+            raster.set_msaa(self.raster.msaa());
+            raster.set_order_independent_transparency(self.raster.order_independent_transparency());
+
+            Supersampling {
+                raster,
+                factor,
+                filter,
+            }
+        });
+    }
+
+    /// Lights with `casts_shadows` get shadow maps with `Some` settings
+    pub fn set_shadows(&mut self, settings: Option<ShadowSettings>) {
+        self.shadows = settings.map(|settings| ShadowPass {
+            raster: Raster::new(settings.resolution, settings.resolution),
+            settings,
+        });
+    }
+
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.cull_mode = cull_mode;
+    }
+
+    pub fn set_front_face(&mut self, front_face: FrontFace) {
+        self.front_face = front_face;
+    }
+
+    /// Renders all instances of the scene with the default [`TexturedShader`]
+    pub fn render_scene(&mut self, scene: &Scene) {
+        self.render_scene_with(scene, &TexturedShader, &TexturedShader);
+    }
+
+    pub fn render_scene_with<V: VertexShader, F: FragmentShader>(
+        &mut self,
+        scene: &Scene,
+        vertex_shader: &V,
+        fragment_shader: &F,
+    ) {
+        self.render_instances(&scene.instances(), vertex_shader, fragment_shader);
+    }
+
+    /// Renders the solid with the default [`TexturedShader`], the solid stays in its model space
     pub fn render_solid(&mut self, solid: &Solid) {
-        self.raster.clear();
+        self.render_solid_with(solid, &TexturedShader, &TexturedShader);
+    }
+
+    pub fn render_solid_with<V: VertexShader, F: FragmentShader>(
+        &mut self,
+        solid: &Solid,
+        vertex_shader: &V,
+        fragment_shader: &F,
+    ) {
+        let instances = [(solid, Matrix4::identity())];
+        self.render_instances(&instances, vertex_shader, fragment_shader);
+    }
 
-        let start = Instant::now();
+    /// Renders each solid with its model matrix into a single frame
+    fn render_instances<V: VertexShader, F: FragmentShader>(
+        &mut self,
+        instances: &[(&Solid, Matrix4<f32>)],
+        vertex_shader: &V,
+        fragment_shader: &F,
+    ) {
+        let view = self.camera.get_view_mat();
+        let shadow_maps = self.render_shadow_maps(instances, view);
 
-        for mesh in &solid.meshes {
-            self.render_mesh(mesh);
+        // The whole pipeline works with `self.raster`, so the larger raster takes its place
+        if let Some(ss) = &mut self.supersampling {
+            std::mem::swap(&mut self.raster, &mut ss.raster);
         }
 
-        let elapsed = Instant::now().duration_since(start);
+        self.draw(
+            instances,
+            view,
+            self.persp,
+            &shadow_maps,
+            vertex_shader,
+            fragment_shader,
+        );
 
-        if elapsed > Duration::from_millis(16) {
-            println!("{elapsed:?}");
+        if let Some(ss) = &mut self.supersampling {
+            std::mem::swap(&mut self.raster, &mut ss.raster);
+            self.raster
+                .downsample_from(&ss.raster, ss.factor, ss.filter);
         }
     }
 
-    fn render_mesh(&mut self, mesh: &Mesh) {
-        for ([v1pos, v2pos, v3pos], [v1tex, v2tex, v3tex]) in
-            mesh.pos_indices.iter().zip(mesh.texcoord_indices.iter())
-        {
-            let pos = &mesh.positions;
-            let tex = &mesh.texcoords;
+    /// Renders the depth of the scene from every light that can cast shadows,
+    /// the returned shadow maps are indexed like the lights
+    fn render_shadow_maps(
+        &mut self,
+        instances: &[(&Solid, Matrix4<f32>)],
+        view: Matrix4<f32>,
+    ) -> Vec<Option<Arc<ShadowMap>>> {
+        let Some(mut pass) = self.shadows.take() else {
+            return Vec::new();
+        };
+
+        let bounds = shadow::bounds(instances);
+        let inv_view = view.invert().unwrap_or_else(Matrix4::identity);
+
+        let mut shadow_maps = Vec::with_capacity(self.lights.len());
+        for i in 0..self.lights.len() {
+            let light = self.lights[i];
+            if !light.casts_shadows() {
+                shadow_maps.push(None);
+                continue;
+            }
+
+            let views = shadow::light_views(&light, bounds);
+            if views.is_empty() {
+                shadow_maps.push(None);
+                continue;
+            }
 
-            let v1 = Vertex::new(pos[*v1pos as usize], tex[*v1tex as usize]);
-            let v2 = Vertex::new(pos[*v2pos as usize], tex[*v2tex as usize]);
-            let v3 = Vertex::new(pos[*v3pos as usize], tex[*v3tex as usize]);
+            let mut faces = Vec::with_capacity(views.len());
+            for (light_view, projection) in views {
+                std::mem::swap(&mut self.raster, &mut pass.raster);
+                self.draw(
+                    instances,
+                    light_view,
+                    projection,
+                    &[],
+                    &ShadowShader,
+                    &ShadowShader,
+                );
+                std::mem::swap(&mut self.raster, &mut pass.raster);
 
-            self.transform_triangle(v1, v2, v3, &mesh.material);
+                faces.push(ShadowFace::new(&pass.raster, light_view, projection));
+            }
+
+            let shadow_map = ShadowMap::new(&light, faces, inv_view, pass.settings);
+            shadow_maps.push(Some(Arc::new(shadow_map)));
         }
+
+        self.shadows = Some(pass);
+        shadow_maps
     }
 
-    fn transform_triangle(
+    /// Clears the raster and renders the instances into it
+    fn draw<V: VertexShader, F: FragmentShader>(
         &mut self,
-        mut v1: Vertex,
-        mut v2: Vertex,
-        mut v3: Vertex,
-        mat: &Material,
+        instances: &[(&Solid, Matrix4<f32>)],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        shadow_maps: &[Option<Arc<ShadowMap>>],
+        vertex_shader: &V,
+        fragment_shader: &F,
     ) {
-        let model = Matrix4::from_angle_y(Deg(270.));
-        let view = self.camera.get_view_mat();
+        self.raster.clear();
+
+        let uniforms: Vec<_> = instances
+            .iter()
+            .map(|(_, model)| Uniforms {
+                shadow_maps: shadow_maps.to_vec(),
+                ..Uniforms::new(*model, view, projection, &self.lights, self.ambient_light)
+            })
+            .collect();
 
-        let transforms = self.persp * view * model;
+        let mut triangles = Vec::new();
+        for ((solid, _), uniforms) in instances.iter().zip(&uniforms) {
+            for mesh in &solid.meshes {
+                self.process_mesh(mesh, vertex_shader, uniforms, &mut triangles);
+            }
+        }
+
+        // Transparent triangles are blended over the finished opaque image from back to front,
+        // the fragment lists of the order-independent transparency are sorted per pixel instead
+        let (mut triangles, mut transparent): (Vec<_>, Vec<_>) = triangles
+            .into_iter()
+            .partition(|tri| tri.material.blend == Blend::Opaque);
+        if !self.raster.order_independent_transparency() {
+            transparent.sort_by(|a, b| b.depth().total_cmp(&a.depth()));
+        }
+        triangles.append(&mut transparent);
+
+        let fragment_stage = FragmentStage {
+            shader: fragment_shader,
+        };
+        self.rasterize(&triangles, &fragment_stage);
+    }
+
+    fn process_mesh<'m, V: VertexShader>(
+        &self,
+        mesh: &'m Mesh,
+        vertex_shader: &V,
+        uniforms: &'m Uniforms,
+        triangles: &mut Vec<Triangle<'m>>,
+    ) {
+        // Attributes have separate indices, `corner` selects the vertex of the triangle
+        let shade_vertex = |triangle: usize, corner: usize| {
+            let index = |indices: &[[u32; 3]]| indices[triangle][corner] as usize;
+            let input = VertexInput {
+                position: mesh.positions[index(&mesh.pos_indices)],
+                normal: mesh.normals[index(&mesh.normal_indices)],
+                texcoords: mesh.texcoords[index(&mesh.texcoord_indices)],
+                tangent: mesh.tangents[index(&mesh.tangent_indices)],
+            };
 
-        v1.transform(transforms);
-        v2.transform(transforms);
-        v3.transform(transforms);
+            let mut varyings = Varyings::default();
+            let pos = vertex_shader.shade(uniforms, &input, &mut varyings);
+            Vertex::new(pos, varyings)
+        };
 
-        if [&v1, &v2, &v3].iter().all(|v| v.pos.z <= 0.)
+        for triangle in 0..mesh.pos_indices.len() {
+            let v1 = shade_vertex(triangle, 0);
+            let v2 = shade_vertex(triangle, 1);
+            let v3 = shade_vertex(triangle, 2);
+
+            self.clip_triangle(v1, v2, v3, &mesh.material, uniforms, triangles);
+        }
+    }
+
+    /// Clips the triangle in clip space and sets up the resulting triangles for rasterization
+    fn clip_triangle<'m>(
+        &self,
+        mut v1: Vertex,
+        mut v2: Vertex,
+        mut v3: Vertex,
+        mat: &'m Material,
+        uniforms: &'m Uniforms,
+        triangles: &mut Vec<Triangle<'m>>,
+    ) {
+        if [&v1, &v2, &v3].iter().all(|v| v.pos.z < -v.pos.w)
             || [&v1, &v2, &v3].iter().all(|v| v.pos.z > v.pos.w)
             || [&v1, &v2, &v3].iter().all(|v| v.pos.y > v.pos.w)
             || [&v1, &v2, &v3].iter().all(|v| v.pos.y < -v.pos.w)
@@ -93,226 +403,542 @@ impl Renderer {
             return;
         }
 
-        v1.dehomog();
-        v2.dehomog();
-        v3.dehomog();
+        let inside = |v: &Vertex| CLIP_PLANES.iter().all(|p| p.dot(v.pos) >= 0.);
+        if inside(&v1) && inside(&v2) && inside(&v3) {
+            v1.dehomog();
+            v2.dehomog();
+            v3.dehomog();
+
+            triangles.extend(self.setup_triangle(v1, v2, v3, mat, uniforms));
+            return;
+        }
+
+        let mut polygon = Self::clip_polygon(vec![v1, v2, v3]);
+        if polygon.len() < 3 {
+            return;
+        }
 
-        self.render_triangle(v1, v2, v3, mat);
+        polygon.iter_mut().for_each(Vertex::dehomog);
+
+        // The clipped polygon is convex, so it can be triangulated as a fan
+        for i in 1..polygon.len() - 1 {
+            triangles.extend(self.setup_triangle(
+                polygon[0].clone(),
+                polygon[i].clone(),
+                polygon[i + 1].clone(),
+                mat,
+                uniforms,
+            ));
+        }
     }
 
-    fn render_triangle(&mut self, mut v1: Vertex, mut v2: Vertex, mut v3: Vertex, mat: &Material) {
-        let v1c = v1.to_screen_coords();
-        let v2c = v2.to_screen_coords();
-        let v3c = v3.to_screen_coords();
+    /// Sutherland–Hodgman clipping of a polygon against the view frustum in clip space
+    fn clip_polygon(mut polygon: Vec<Vertex>) -> Vec<Vertex> {
+        for plane in CLIP_PLANES {
+            if polygon.is_empty() {
+                break;
+            }
+
+            let mut clipped = Vec::with_capacity(polygon.len() + 1);
+
+            for (i, cur) in polygon.iter().enumerate() {
+                let next = &polygon[(i + 1) % polygon.len()];
 
-        let minx = [v1c, v2c, v3c].iter().map(|v| v.x).min().unwrap();
-        let maxx = [v1c, v2c, v3c].iter().map(|v| v.x).max().unwrap();
-        let miny = [v1c, v2c, v3c].iter().map(|v| v.y).min().unwrap();
-        let maxy = [v1c, v2c, v3c].iter().map(|v| v.y).max().unwrap();
+                let cur_dist = plane.dot(cur.pos);
+                let next_dist = plane.dot(next.pos);
 
-        let area = (v2c.x as f32 - v1c.x as f32) * (v3c.y as f32 - v1c.y as f32)
-            - (v3c.x as f32 - v1c.x as f32) * (v2c.y as f32 - v1c.y as f32);
+                if cur_dist >= 0. {
+                    clipped.push(cur.clone());
+                }
 
-        for y in miny..maxy {
-            for x in minx..maxx {
-                self.draw_pixel(x, y, v1c, v2c, v3c, &v1, &v2, &v3, area as f32, mat);
+                if (cur_dist >= 0.) != (next_dist >= 0.) {
+                    let t = cur_dist / (cur_dist - next_dist);
+                    clipped.push(Vertex::lerp_edge(cur, next, t));
+                }
             }
+
+            polygon = clipped;
         }
 
-        /* for y in miny..=maxy {
-            for x in (minx..=maxx).step_by(8) {
-                /* if x + 7 > maxx {
-                    for x in x..=maxx {
-                        self.draw_pixel(x, y, v1c, v2c, v3c, &v1, &v2, &v3, area as f32, mat);
-                    }
-                } else { */
-                let xs = i32x8::from_array([x, x + 1, x + 2, x + 3, x + 4, x + 5, x + 6, x + 7]);
-                self.draw_pixel_simd(xs, y, v1c, v2c, v3c, &v1, &v2, &v3, area as f32, mat);
-                /* } */
+        polygon
+    }
+
+    /// Snaps the triangle to the subpixel grid and sets up its edge functions,
+    /// returns `None` for degenerate and culled triangles
+    fn setup_triangle<'m>(
+        &self,
+        v1: Vertex,
+        mut v2: Vertex,
+        mut v3: Vertex,
+        mat: &'m Material,
+        uniforms: &'m Uniforms,
+    ) -> Option<Triangle<'m>> {
+        let (width, height) = (self.raster.width(), self.raster.height());
+        let v1c = v1.to_screen_coords(width, height);
+        let mut v2c = v2.to_screen_coords(width, height);
+        let mut v3c = v3.to_screen_coords(width, height);
+
+        let mut area = Edge::orient(v1c, v2c, v3c);
+        if area == 0 {
+            return None;
+        }
+
+        // The y axis of the screen points down, which flips the winding
+        let ccw = area < 0;
+        let front = match self.front_face {
+            FrontFace::Ccw => ccw,
+            FrontFace::Cw => !ccw,
+        };
+        let front = front != uniforms.mirrored;
+
+        match self.cull_mode {
+            _ if mat.double_sided => {}
+            CullMode::Back if !front => return None,
+            CullMode::Front if front => return None,
+            _ => {}
+        }
+
+        // The edge functions expect the interior of the triangle on their positive side
+        if area < 0 {
+            std::mem::swap(&mut v2, &mut v3);
+            std::mem::swap(&mut v2c, &mut v3c);
+            area = -area;
+        }
+
+        let edges = [
+            Edge::new(v2c, v3c),
+            Edge::new(v3c, v1c),
+            Edge::new(v1c, v2c),
+        ];
+        let inv_area = 1. / area as f32;
+
+        // The interpolated vertex is linear in the barycentric weights,
+        // so the steps of the edge functions give its screen-space derivatives
+        let [e1, e2, e3] = edges.map(|e| (e.step_x as f32 * inv_area, e.step_y as f32 * inv_area));
+        let ddx = Vertex::lerp(&v1, &v2, &v3, e1.0, e2.0, e3.0);
+        let ddy = Vertex::lerp(&v1, &v2, &v3, e1.1, e2.1, e3.1);
+
+        let minx = [v1c, v2c, v3c].iter().map(|v| v.x).min().unwrap() >> SUBPIXEL_BITS;
+        let maxx = [v1c, v2c, v3c].iter().map(|v| v.x).max().unwrap() >> SUBPIXEL_BITS;
+        let miny = [v1c, v2c, v3c].iter().map(|v| v.y).min().unwrap() >> SUBPIXEL_BITS;
+        let maxy = [v1c, v2c, v3c].iter().map(|v| v.y).max().unwrap() >> SUBPIXEL_BITS;
+
+        Some(Triangle {
+            vertices: [v1, v2, v3],
+            edges,
+            inv_area,
+            ddx,
+            ddy,
+            min: Vector2::new(minx.max(0) as i32, miny.max(0) as i32),
+            max: Vector2::new(
+                maxx.min(width as i64 - 1) as i32,
+                maxy.min(height as i64 - 1) as i32,
+            ),
+            material: mat,
+            uniforms,
+        })
+    }
+
+    /// Sorts the triangles into screen tiles and rasterizes the tiles
+    fn rasterize<F: FragmentShader>(&mut self, triangles: &[Triangle], fs: &FragmentStage<F>) {
+        let tile_count = self.raster.height().div_ceil(TILE_HEIGHT);
+
+        // Triangles keep their submission order inside of the bins,
+        // so the depth test resolves ties the same way regardless of the threading
+        let mut bins = vec![Vec::new(); tile_count];
+        for (i, tri) in triangles.iter().enumerate() {
+            if tri.min.y > tri.max.y {
+                continue;
             }
-        } */
+
+            let first = tri.min.y as usize / TILE_HEIGHT;
+            let last = tri.max.y as usize / TILE_HEIGHT;
+
+            for bin in &mut bins[first..=last] {
+                bin.push(i);
+            }
+        }
+
+        let tiles = self.raster.tiles_mut(TILE_HEIGHT);
+        let simd = self.simd;
+
+        if self.parallel {
+            tiles.into_par_iter().zip(bins).for_each(|(mut tile, bin)| {
+                Self::render_tile(&mut tile, &bin, triangles, fs, simd)
+            });
+        } else {
+            tiles.into_iter().zip(bins).for_each(|(mut tile, bin)| {
+                Self::render_tile(&mut tile, &bin, triangles, fs, simd)
+            });
+        }
     }
 
-    /* fn draw_pixel_simd(
-        &mut self,
-        xs: i32x8,
-        y: i32,
-        v1c: Vector2<i32>,
-        v2c: Vector2<i32>,
-        v3c: Vector2<i32>,
-        v1: &Vertex,
-        v2: &Vertex,
-        v3: &Vertex,
-        total_area: f32,
-        mat: &Material,
+    fn render_tile<F: FragmentShader>(
+        tile: &mut Tile,
+        bin: &[usize],
+        triangles: &[Triangle],
+        fs: &FragmentStage<F>,
+        simd: bool,
+    ) {
+        for &i in bin {
+            Self::render_triangle(tile, &triangles[i], fs, simd);
+        }
+
+        tile.resolve();
+    }
+
+    fn render_triangle<F: FragmentShader>(
+        tile: &mut Tile,
+        tri: &Triangle,
+        fs: &FragmentStage<F>,
+        simd: bool,
     ) {
-        let (v1t, v2t, v3t) = Self::barycentric_simd(xs, y, v1c, v2c, v3c, total_area);
-        for i in 0..8 {
-            if v1t[i] >= 0. && v2t[i] >= 0. && v3t[i] >= 0. {
-                let v = Vertex::lerp(v1, v2, v3, v1t[i], v2t[i], v3t[i]);
-                let z = v.pos.z;
-                let tex_color = Self::sample_texture(v, mat);
-                self.raster
-                    .set_pixel(xs[i] as usize, y as usize, tex_color, z);
+        let miny = tri.min.y.max(tile.y_start() as i32);
+        let maxy = tri.max.y.min(tile.y_end() as i32 - 1);
+        if miny > maxy {
+            return;
+        }
+
+        let mut row = tri.edges.map(|e| e.eval(tri.min.x, miny));
+
+        for y in miny..=maxy {
+            let mut e = row;
+
+            // The SIMD path works with whole pixels
+            if simd && tile.msaa() == Msaa::Off {
+                for x in (tri.min.x..=tri.max.x).step_by(8) {
+                    Self::draw_pixels_simd(tile, x, y, tri, e, fs);
+
+                    for (e, edge) in e.iter_mut().zip(&tri.edges) {
+                        *e += edge.step_x * 8;
+                    }
+                }
+            } else {
+                for x in tri.min.x..=tri.max.x {
+                    Self::draw_pixel(tile, x, y, tri, e, fs);
+
+                    for (e, edge) in e.iter_mut().zip(&tri.edges) {
+                        *e += edge.step_x;
+                    }
+                }
+            }
+
+            for (row, edge) in row.iter_mut().zip(&tri.edges) {
+                *row += edge.step_y;
             }
         }
-    } */
+    }
 
-    fn draw_pixel(
-        &mut self,
+    /// Draws 8 consecutive pixels of a span starting at `x`,
+    /// `e` are the values of the edge functions at `x`
+    fn draw_pixels_simd<F: FragmentShader>(
+        tile: &mut Tile,
         x: i32,
         y: i32,
-        v1c: Vector2<i32>,
-        v2c: Vector2<i32>,
-        v3c: Vector2<i32>,
-        v1: &Vertex,
-        v2: &Vertex,
-        v3: &Vertex,
-        total_area: f32,
-        mat: &Material,
+        tri: &Triangle,
+        e: [i64; 3],
+        fs: &FragmentStage<F>,
     ) {
-        let (v1t, v2t, v3t) = Self::barycentric(x, y, v1c, v2c, v3c, total_area);
-        if v1t >= 0. && v2t >= 0. && v3t >= 0. {
-            let v = Vertex::lerp(v1, v2, v3, v1t, v2t, v3t);
-            let z = v.pos.z;
-            let tex_color = Self::sample_texture(v, mat);
-            self.raster.set_pixel(x as usize, y as usize, tex_color, z);
+        let lanes = i64x8::from_array([0, 1, 2, 3, 4, 5, 6, 7]);
+        let e = [0, 1, 2].map(|i| i64x8::splat(e[i]) + i64x8::splat(tri.edges[i].step_x) * lanes);
+
+        // The tail of the span doesn't have to be a multiple of 8
+        let xs = i32x8::splat(x) + lanes.cast();
+        let in_span = xs.simd_le(i32x8::splat(tri.max.x));
+
+        let mut covered = in_span.cast::<i64>();
+        for (e, edge) in e.iter().zip(&tri.edges) {
+            covered &= e.simd_ge(i64x8::splat(edge.threshold));
         }
+        if !covered.any() {
+            return;
+        }
+
+        let inv_area = f32x8::splat(tri.inv_area);
+        let [v1t, v2t, v3t] = e.map(|e| e.cast::<f32>() * inv_area);
+
+        let [v1, v2, v3] = &tri.vertices;
+        let lerp = |a: f32, b: f32, c: f32| {
+            v1t * f32x8::splat(a) + v2t * f32x8::splat(b) + v3t * f32x8::splat(c)
+        };
+
+        let z = lerp(v1.pos.z, v2.pos.z, v3.pos.z);
+        let mut mask = tile.depth_test_x8(x as usize, y as usize, z, covered.cast());
+        if !mask.any() {
+            return;
+        }
+
+        let one = lerp(v1.one, v2.one, v3.one);
+        let (ddx_one, ddy_one) = (f32x8::splat(tri.ddx.one), f32x8::splat(tri.ddy.one));
+
+        let mut varyings = [Varyings::default(); 8];
+        let mut ddx = [Varyings::default(); 8];
+        let mut ddy = [Varyings::default(); 8];
+        for k in 0..MAX_VARYINGS {
+            let values = lerp(v1.varyings[k], v2.varyings[k], v3.varyings[k]) / one;
+            let dx = (f32x8::splat(tri.ddx.varyings[k]) - values * ddx_one) / one;
+            let dy = (f32x8::splat(tri.ddy.varyings[k]) - values * ddy_one) / one;
+
+            for lane in 0..8 {
+                varyings[lane][k] = values[lane];
+                ddx[lane][k] = dx[lane];
+                ddy[lane][k] = dy[lane];
+            }
+        }
+
+        let mut cols = [0; 8];
+        for (i, col) in cols.iter_mut().enumerate() {
+            if !mask.test(i) {
+                continue;
+            }
+
+            let fragment = Fragment {
+                x: x as usize + i,
+                y: y as usize,
+                depth: z[i],
+                varyings: varyings[i],
+                ddx: ddx[i],
+                ddy: ddy[i],
+            };
+
+            // Blended fragments can't be written together, they read the raster
+            match (fs.shade(tri, &fragment), tri.material.blend) {
+                (Some(c), Blend::Opaque) => *col = pack_color(c.truncate()),
+                (Some(c), blend) => {
+                    tile.blend_pixel(fragment.x, fragment.y, 1, c, fragment.depth, blend);
+                    mask.set(i, false);
+                }
+                (None, _) => mask.set(i, false),
+            }
+        }
+
+        tile.set_pixels_x8(x as usize, y as usize, u32x8::from_array(cols), z, mask);
     }
 
-    /* fn barycentric_simd(
-        xs: i32x8,
-        y: i32,
-        v1c: Vector2<i32>,
-        v2c: Vector2<i32>,
-        v3c: Vector2<i32>,
-        total_area: f32,
-    ) -> (f32x8, f32x8, f32x8) {
-        let c_sub_area = i32x8::splat(v2c.x - v1c.x) * i32x8::splat(y - v1c.y)
-            - (xs - i32x8::splat(v1c.x)) * i32x8::splat(v2c.y - v1c.y);
-        let b_sub_area = i32x8::splat(v1c.x - v3c.x) * i32x8::splat(y - v3c.y)
-            - (xs - i32x8::splat(v3c.x)) * i32x8::splat(v1c.y - v3c.y);
-
-        let ct = c_sub_area.cast::<f32>() / f32x8::splat(total_area);
-        let bt = b_sub_area.cast::<f32>() / f32x8::splat(total_area);
-        let at = f32x8::splat(1.) - (ct + bt);
-
-        (at, bt, ct)
-    } */
-
-    fn barycentric(
+    /// `e` are the values of the edge functions at the pixel center.
+    ///
+    /// Coverage and depth are tested for every sample of the pixel,
+    /// but the pixel is shaded only once at its center.
+    fn draw_pixel<F: FragmentShader>(
+        tile: &mut Tile,
         x: i32,
         y: i32,
-        v1c: Vector2<i32>,
-        v2c: Vector2<i32>,
-        v3c: Vector2<i32>,
-        total_area: f32,
-    ) -> (f32, f32, f32) {
-        let c_sub_area = (v2c.x - v1c.x) * (y - v1c.y) - (x - v1c.x) * (v2c.y - v1c.y);
-        let b_sub_area = (v1c.x - v3c.x) * (y - v3c.y) - (x - v3c.x) * (v1c.y - v3c.y);
-
-        let ct = c_sub_area as f32 / total_area;
-        let bt = b_sub_area as f32 / total_area;
-        let at = 1. - (ct + bt);
-
-        (at, bt, ct)
-    }
-
-    fn sample_texture(v: Vertex, mat: &Material) -> u32 {
-        let tex = &mat.diffuse_texture;
-        let tx = v.texcoords().x * tex.width as f32;
-        let ty = v.texcoords().y * tex.height as f32;
-
-        let col = tex.get_pixel(tx as usize, tex.height as usize - 1 - ty as usize);
-        u32::from_be_bytes([0, col.x, col.y, col.z])
-
-        // https://en.wikipedia.org/wiki/Bilinear_interpolation#Weighted_mean
-        /* let x1 = tx.round();
-        let x2 = (tx + 1.).round();
-        let y1 = (ty + 1.).round();
-        let y2 = ty.round();
-
-        let rect_area = (x2 - x1) * (y2 - y1);
-        let w_bot_l = ((x2 - tx) * (y2 - ty)) / rect_area;
-        let w_top_l = ((x2 - tx) * (ty - y1)) / rect_area;
-        let w_bot_r = ((tx - x1) * (y2 - ty)) / rect_area;
-        let w_top_r = ((tx - x1) * (ty - y1)) / rect_area;
-
-        let mut top_lc = tex
-            .get_pixel(
-                (x1 as u32).clamp(0, tex.width - 1) as usize,
-                (tex.height - (y2 as u32).clamp(0, tex.height - 1)) as usize,
-            )
-            .cast::<f32>()
-            .unwrap();
-        let mut top_rc = tex
-            .get_pixel(
-                (x2 as u32).clamp(0, tex.width - 1) as usize,
-                (tex.height - (y2 as u32).clamp(0, tex.height - 1)) as usize,
-            )
-            .cast::<f32>()
-            .unwrap();
-        let mut bot_lc = tex
-            .get_pixel(
-                (x1 as u32).clamp(0, tex.width - 1) as usize,
-                (tex.height - (y1 as u32).clamp(0, tex.height - 1)) as usize,
-            )
-            .cast::<f32>()
-            .unwrap();
-        let mut bot_rc = tex
-            .get_pixel(
-                (x2 as u32).clamp(0, tex.width - 1) as usize,
-                (tex.height - (y1 as u32).clamp(0, tex.height - 1)) as usize,
-            )
-            .cast::<f32>()
-            .unwrap();
-
-        top_lc *= w_top_l;
-        top_rc *= w_top_r;
-        bot_lc *= w_bot_l;
-        bot_rc *= w_bot_r;
-
-        let res_c = top_lc + top_rc + bot_lc + bot_rc;
-        u32::from_be_bytes([0, res_c[0] as u8, res_c[1] as u8, res_c[2] as u8]) */
+        tri: &Triangle,
+        e: [i64; 3],
+        fs: &FragmentStage<F>,
+    ) {
+        let (x, y) = (x as usize, y as usize);
+        let [v1t, v2t, v3t] = e.map(|e| e as f32 * tri.inv_area);
+        let [v1, v2, v3] = &tri.vertices;
+
+        // Same order of operations as in Vertex::lerp, so the paths give identical depths
+        let z = v1.pos.z * v1t + v2.pos.z * v2t + v3.pos.z * v3t;
+
+        let mut coverage = 0u8;
+        let mut depths = [0.; MAX_SAMPLES];
+        for (sample, &(sx, sy)) in tile.sample_positions().iter().enumerate() {
+            let covered = e
+                .iter()
+                .zip(&tri.edges)
+                .all(|(e, edge)| e + edge.sample_offset(sx, sy) >= edge.threshold);
+            if !covered {
+                continue;
+            }
+
+            let grid = SAMPLE_GRID as f32;
+            let sample_z =
+                z + tri.ddx.pos.z * (sx as f32 / grid) + tri.ddy.pos.z * (sy as f32 / grid);
+            if tile.depth_test(x, y, sample, sample_z) {
+                coverage |= 1 << sample;
+                depths[sample] = sample_z;
+            }
+        }
+
+        if coverage == 0 {
+            return;
+        }
+
+        let v = Vertex::lerp(v1, v2, v3, v1t, v2t, v3t);
+        let fragment = Fragment {
+            x,
+            y,
+            depth: z,
+            varyings: v.varyings(),
+            ddx: v.derivatives(&tri.ddx),
+            ddy: v.derivatives(&tri.ddy),
+        };
+
+        let Some(col) = fs.shade(tri, &fragment) else {
+            return;
+        };
+
+        match tri.material.blend {
+            Blend::Opaque => {
+                let col = pack_color(col.truncate());
+                for (sample, depth) in depths.iter().enumerate() {
+                    if coverage & (1 << sample) != 0 {
+                        tile.set_sample(x, y, sample, col, *depth);
+                    }
+                }
+            }
+            blend => tile.blend_pixel(x, y, coverage, col, z, blend),
+        }
+    }
+}
+
+/// Fragment shader of the draw call followed by the alpha test of the material
+struct FragmentStage<'s, F> {
+    shader: &'s F,
+}
+
+impl<F: FragmentShader> FragmentStage<'_, F> {
+    /// Returns the RGBA color of the fragment, `None` if it was discarded
+    fn shade(&self, tri: &Triangle, fragment: &Fragment) -> Option<Vector4<f32>> {
+        let col = self.shader.shade(tri.uniforms, tri.material, fragment)?;
+
+        match tri.material.alpha_cutoff {
+            Some(cutoff) if col.w < cutoff => None,
+            _ => Some(col),
+        }
+    }
+}
+
+/// Triangle in screen space, ready for rasterization
+struct Triangle<'m> {
+    vertices: [Vertex; 3],
+    /// Each edge function gives the barycentric weight of the opposite vertex
+    edges: [Edge; 3],
+    inv_area: f32,
+    /// Change of the interpolated vertex when stepping by one pixel in x and y
+    ddx: Vertex,
+    ddy: Vertex,
+    /// Inclusive bounding box in pixels clamped to the raster
+    min: Vector2<i32>,
+    max: Vector2<i32>,
+    material: &'m Material,
+    /// Uniforms of the instance the triangle belongs to
+    uniforms: &'m Uniforms,
+}
+
+impl Triangle<'_> {
+    /// Depth of the centroid in normalized device coordinates
+    fn depth(&self) -> f32 {
+        self.vertices.iter().map(|v| v.pos.z).sum::<f32>() / 3.
+    }
+}
+
+/// Edge function of a triangle in fixed point, evaluated at pixel centers
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    /// Change of the value when stepping by one pixel
+    step_x: i64,
+    step_y: i64,
+    /// Value at the center of pixel (0, 0)
+    origin: i64,
+    /// Minimal value of a covered pixel, implements the top-left fill rule
+    threshold: i64,
+}
+
+impl Edge {
+    fn new(a: Vector2<i64>, b: Vector2<i64>) -> Self {
+        let dx = b.x - a.x;
+        let dy = b.y - a.y;
+        let half = SUBPIXEL_SCALE / 2;
+
+        // The interior lies on the positive side (y points down),
+        // so a top edge goes to the right and a left edge goes up.
+        // Pixel centers lying exactly on other edges belong to the neighbouring triangle.
+        let top_left = (dy == 0 && dx > 0) || dy < 0;
+
+        Self {
+            step_x: -dy * SUBPIXEL_SCALE,
+            step_y: dx * SUBPIXEL_SCALE,
+            origin: dx * (half - a.y) - dy * (half - a.x),
+            threshold: if top_left { 0 } else { 1 },
+        }
+    }
+
+    /// Twice the signed area of the triangle (a, b, p)
+    fn orient(a: Vector2<i64>, b: Vector2<i64>, p: Vector2<i64>) -> i64 {
+        (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+    }
+
+    fn eval(&self, x: i32, y: i32) -> i64 {
+        self.step_x * x as i64 + self.step_y * y as i64 + self.origin
+    }
+
+    /// Change of the value when moving from the pixel center to a sample position,
+    /// the division is exact as the steps are multiples of `SUBPIXEL_SCALE`
+    fn sample_offset(&self, sx: i32, sy: i32) -> i64 {
+        (self.step_x * sx as i64 + self.step_y * sy as i64) / SAMPLE_GRID as i64
     }
 }
 
 #[derive(Debug, Clone)]
 struct Vertex {
     pos: Vector4<f32>,
-    texcoords: Vector2<f32>,
+    varyings: Varyings,
     one: f32,
 }
 
 impl Vertex {
-    fn new(pos: Vector4<f32>, texcoords: Vector2<f32>) -> Self {
+    fn new(pos: Vector4<f32>, varyings: Varyings) -> Self {
         Self {
             pos,
-            texcoords,
+            varyings,
             one: 1.,
         }
     }
 
-    fn transform(&mut self, mat: Matrix4<f32>) {
-        self.pos = mat * self.pos;
-    }
-
     fn dehomog(&mut self) {
         let w = self.pos.w;
         self.pos /= w;
-        self.texcoords /= w;
+        self.varyings *= 1. / w;
         self.one /= w;
     }
 
-    fn texcoords(&self) -> Vector2<f32> {
-        self.texcoords * (1. / self.one)
+    /// Perspective-correct varyings of an interpolated vertex
+    fn varyings(&self) -> Varyings {
+        let mut varyings = self.varyings;
+        for k in 0..MAX_VARYINGS {
+            varyings[k] /= self.one;
+        }
+
+        varyings
+    }
+
+    /// Derivatives of the perspective-correct varyings, `d` is the derivative of the vertex itself
+    fn derivatives(&self, d: &Vertex) -> Varyings {
+        let mut derivatives = Varyings::default();
+        for k in 0..MAX_VARYINGS {
+            // Quotient rule on varyings / one
+            let value = self.varyings[k] / self.one;
+            derivatives[k] = (d.varyings[k] - value * d.one) / self.one;
+        }
+
+        derivatives
+    }
+
+    /// Screen coordinates in fixed point with `SUBPIXEL_BITS` fractional bits
+    fn to_screen_coords(&self, width: usize, height: usize) -> Vector2<i64> {
+        let x = 0.5 * width as f32 * (self.pos.x + 1.);
+        let y = 0.5 * height as f32 * (1. - self.pos.y);
+
+        let scale = SUBPIXEL_SCALE as f32;
+        Vector2::new((x * scale).round() as i64, (y * scale).round() as i64)
     }
 
-    fn to_screen_coords(&mut self) -> Vector2<i32> {
-        let x = 0.5 * (WIDTH - 1) as f32 * (self.pos.x + 1.);
-        let y = 0.5 * (HEIGHT - 1) as f32 * (1. - self.pos.y);
+    /// Linear interpolation between two vertices, `t` == 0 gives `v1`
+    fn lerp_edge(v1: &Vertex, v2: &Vertex, t: f32) -> Vertex {
+        let mut v1 = v1.clone();
+        v1 *= 1. - t;
+
+        let mut v2 = v2.clone();
+        v2 *= t;
+
+        v1 += v2;
 
-        Vector2::new(x as i32, y as i32)
+        v1
     }
 
     fn lerp(v1: &Vertex, v2: &Vertex, v3: &Vertex, v1t: f32, v2t: f32, v3t: f32) -> Vertex {
@@ -335,7 +961,7 @@ impl Vertex {
 impl MulAssign<f32> for Vertex {
     fn mul_assign(&mut self, rhs: f32) {
         self.pos *= rhs;
-        self.texcoords *= rhs;
+        self.varyings *= rhs;
         self.one *= rhs;
     }
 }
@@ -343,7 +969,283 @@ impl MulAssign<f32> for Vertex {
 impl AddAssign<Vertex> for Vertex {
     fn add_assign(&mut self, rhs: Vertex) {
         self.pos += rhs.pos;
-        self.texcoords += rhs.texcoords;
+        self.varyings += rhs.varyings;
         self.one += rhs.one;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point3, Vector2};
+
+    use crate::scene::Transform;
+
+    use super::*;
+
+    /// Positions are in clip space after the model matrix, the color is taken from the texture
+    /// coordinates
+    struct ClipSpaceShader;
+
+    impl VertexShader for ClipSpaceShader {
+        fn shade(
+            &self,
+            uniforms: &Uniforms,
+            input: &VertexInput,
+            varyings: &mut Varyings,
+        ) -> Vector4<f32> {
+            varyings.set_vec2(0, input.texcoords);
+            uniforms.model * input.position
+        }
+    }
+
+    impl FragmentShader for ClipSpaceShader {
+        fn shade(
+            &self,
+            _uniforms: &Uniforms,
+            _material: &Material,
+            fragment: &Fragment,
+        ) -> Option<Vector4<f32>> {
+            let uv = fragment.varyings.vec2(0);
+            Some(Vector4::new(uv.x, uv.y, 0.5, 1.))
+        }
+    }
+
+    const WIDTH: usize = 96;
+    const HEIGHT: usize = 72;
+
+    /// Pixel center in normalized device coordinates
+    fn ndc(x: i32, y: i32) -> Vector2<f32> {
+        Vector2::new(
+            (x as f32 + 0.5) / WIDTH as f32 * 2. - 1.,
+            1. - (y as f32 + 0.5) / HEIGHT as f32 * 2.,
+        )
+    }
+
+    /// Square of 48x48 pixels split into a fan around its center. All of the vertices lie
+    /// on pixel centers, so many pixels lie exactly on the edges shared by two triangles.
+    fn fan(texcoords: impl Fn(Vector2<f32>) -> Vector2<f32>, blend: Blend) -> Solid {
+        let (cx, cy) = (WIDTH as i32 / 2, HEIGHT as i32 / 2);
+
+        // Walks around the square, so consecutive points form the triangles of the fan
+        let side = (-24..24).step_by(4);
+        let ring: Vec<_> = side
+            .clone()
+            .map(|t| (t, -24))
+            .chain(side.clone().map(|t| (24, t)))
+            .chain(side.clone().map(|t| (-t, 24)))
+            .chain(side.map(|t| (-24, -t)))
+            .collect();
+
+        let mut corners = vec![ndc(cx, cy)];
+        corners.extend(ring.iter().map(|(dx, dy)| ndc(cx + dx, cy + dy)));
+
+        let positions = corners.iter().flat_map(|c| [c.x, c.y, 0.]).collect();
+        let texcoords = corners
+            .iter()
+            .map(|c| texcoords(*c))
+            .flat_map(|t| [t.x, t.y])
+            .collect();
+
+        let n = ring.len() as u32;
+        let indices: Vec<u32> = (1..=n).flat_map(|i| [0, i, i % n + 1]).collect();
+
+        let material = Material {
+            blend,
+            ..Material::default()
+        };
+
+        Solid::new(vec![Mesh::new(
+            positions,
+            indices.clone(),
+            Vec::new(),
+            Vec::new(),
+            texcoords,
+            indices,
+            material,
+        )])
+    }
+
+    /// Two triangles covering the whole screen with a single color
+    fn full_screen(color: Vector2<f32>) -> Solid {
+        let positions = vec![-1., -1., 0., 1., -1., 0., 1., 1., 0., -1., 1., 0.];
+        let indices = vec![0, 1, 2, 0, 2, 3];
+
+        Solid::new(vec![Mesh::new(
+            positions,
+            indices,
+            Vec::new(),
+            Vec::new(),
+            vec![color.x, color.y],
+            vec![0; 6],
+            Material::default(),
+        )])
+    }
+
+    fn render(solid: &Solid, configure: impl FnOnce(&mut Renderer)) -> Vec<u32> {
+        let camera = Camera::new(Point3::new(0., 0., 0.), 1., 1.);
+        let mut renderer = Renderer::new(Raster::new(WIDTH, HEIGHT), camera);
+        configure(&mut renderer);
+        renderer.render_solid_with(solid, &ClipSpaceShader, &ClipSpaceShader);
+
+        renderer.img_buf().to_vec()
+    }
+
+    #[test]
+    fn fan_covers_every_pixel_once() {
+        // Additive blending would reveal any pixel drawn twice
+        let quarter = Vector2::new(0.25, 0.25);
+        let solid = fan(|_| quarter, Blend::Additive);
+        let img = render(&solid, |_| {});
+
+        let once = pack_color(Vector3::new(0.25, 0.25, 0.5));
+        assert!(img.iter().all(|col| *col == 0 || *col == once));
+
+        // Pixels inside of the square can't be left out by cracks between the triangles
+        for (i, col) in img.iter().enumerate() {
+            let dx = (i % WIDTH) as i32 - WIDTH as i32 / 2;
+            let dy = (i / WIDTH) as i32 - HEIGHT as i32 / 2;
+            if dx.abs() < 24 && dy.abs() < 24 {
+                assert_eq!(*col, once);
+            }
+        }
+    }
+
+    /// Colors that differ across the whole fan, so every pixel depends on its triangle
+    fn gradient(ndc: Vector2<f32>) -> Vector2<f32> {
+        ndc * 0.5 + Vector2::new(0.5, 0.5)
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let solid = fan(gradient, Blend::Opaque);
+        for msaa in [Msaa::Off, Msaa::X4] {
+            let parallel = render(&solid, |r| r.set_msaa(msaa));
+            let serial = render(&solid, |r| {
+                r.set_msaa(msaa);
+                r.set_parallel(false);
+            });
+
+            assert!(parallel.iter().any(|col| *col != 0));
+            assert_eq!(parallel, serial);
+        }
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        let solid = fan(gradient, Blend::Opaque);
+        for msaa in [Msaa::Off, Msaa::X4] {
+            let simd = render(&solid, |r| {
+                r.set_msaa(msaa);
+                r.set_simd(true);
+            });
+            let scalar = render(&solid, |r| r.set_msaa(msaa));
+
+            assert!(simd.iter().any(|col| *col != 0));
+            assert_eq!(simd, scalar);
+        }
+    }
+
+    #[test]
+    fn near_plane_clips_polygon() {
+        // The varying follows z, so the interpolation along the clipped edges can be checked
+        let vertex = |x: f32, y: f32, z: f32, w: f32| {
+            let mut varyings = Varyings::default();
+            varyings[0] = z;
+            Vertex::new(Vector4::new(x, y, z, w), varyings)
+        };
+        let polygon = vec![
+            vertex(0., 0.5, -3., 1.),
+            vertex(-0.5, -0.5, 0.5, 1.),
+            vertex(0.5, -0.5, 0.5, 1.),
+        ];
+
+        let clipped = Renderer::clip_polygon(polygon);
+
+        assert_eq!(clipped.len(), 4);
+        for v in &clipped {
+            assert!(v.pos.z >= -v.pos.w - 1e-6);
+            assert!((v.varyings[0] - v.pos.z).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn geometry_behind_near_plane_is_rejected() {
+        let mut solid = fan(gradient, Blend::Opaque);
+        for p in &mut solid.meshes[0].positions {
+            p.z = -2.;
+        }
+
+        assert!(render(&solid, |_| {}).iter().all(|col| *col == 0));
+    }
+
+    #[test]
+    fn supersampling_keeps_flat_color() {
+        let solid = full_screen(Vector2::new(0.3, 0.8));
+        let plain = render(&solid, |_| {});
+        assert!(plain.iter().all(|col| *col == plain[0] && *col != 0));
+
+        for filter in [
+            DownsampleFilter::Box,
+            DownsampleFilter::Tent,
+            DownsampleFilter::Lanczos,
+        ] {
+            for factor in [2, 3] {
+                let supersampled = render(&solid, |r| r.set_supersampling(factor, filter));
+                assert_eq!(supersampled, plain, "{filter:?} x{factor}");
+            }
+        }
+    }
+
+    /// Triangle in the middle of the screen, with the winding as seen without mirroring
+    fn triangle(clockwise: bool) -> Solid {
+        let positions = vec![-0.5, -0.5, 0., 0., 0.5, 0., 0.5, -0.5, 0.];
+        let indices = if clockwise {
+            vec![0, 1, 2]
+        } else {
+            vec![0, 2, 1]
+        };
+
+        Solid::new(vec![Mesh::new(
+            positions,
+            indices,
+            Vec::new(),
+            Vec::new(),
+            vec![0.5, 0.5],
+            vec![0; 3],
+            Material::default(),
+        )])
+    }
+
+    #[test]
+    fn back_faces_are_culled() {
+        let solid = triangle(true);
+
+        let back = render(&solid, |r| r.set_cull_mode(CullMode::Back));
+        assert!(back.iter().all(|col| *col == 0));
+
+        let front = render(&solid, |r| r.set_cull_mode(CullMode::Front));
+        assert!(front.iter().any(|col| *col != 0));
+    }
+
+    #[test]
+    fn mirrored_instances_keep_front_faces() {
+        // Mirroring makes the front face clockwise on the screen, but it must not be culled
+        let mut scene = Scene::new();
+        let solid = scene.add_solid(triangle(false));
+        let mirror = Transform {
+            scale: Vector3::new(-1., 1., 1.),
+            ..Default::default()
+        };
+        scene.add_node(mirror, Some(solid), None);
+
+        let camera = Camera::new(Point3::new(0., 0., 0.), 1., 1.);
+        let mut renderer = Renderer::new(Raster::new(WIDTH, HEIGHT), camera);
+        renderer.set_cull_mode(CullMode::Back);
+        renderer.render_scene_with(&scene, &ClipSpaceShader, &ClipSpaceShader);
+        assert!(renderer.img_buf().iter().any(|col| *col != 0));
+
+        renderer.set_cull_mode(CullMode::Front);
+        renderer.render_scene_with(&scene, &ClipSpaceShader, &ClipSpaceShader);
+        assert!(renderer.img_buf().iter().all(|col| *col == 0));
+    }
+}
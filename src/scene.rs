@@ -0,0 +1,132 @@
+use cgmath::{Matrix4, One, Quaternion, SquareMatrix, Vector3};
+
+use crate::solid::Solid;
+
+/// Translation, rotation and scale of a node relative to its parent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn new(translation: Vector3<f32>, rotation: Quaternion<f32>, scale: Vector3<f32>) -> Self {
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    /// Scales first, then rotates and translates
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new(
+            Vector3::new(0., 0., 0.),
+            Quaternion::one(),
+            Vector3::new(1., 1., 1.),
+        )
+    }
+}
+
+/// Handle of a solid owned by a [`Scene`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SolidId(usize);
+
+/// Handle of a node of a [`Scene`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+pub struct Node {
+    pub transform: Transform,
+    /// Nodes without a solid only group their children
+    pub solid: Option<SolidId>,
+    parent: Option<NodeId>,
+}
+
+impl Node {
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+}
+
+/// Hierarchy of nodes placing solids in the world.
+///
+/// A solid can be referenced by any number of nodes, each of them draws one instance of it.
+#[derive(Default)]
+pub struct Scene {
+    solids: Vec<Solid>,
+    nodes: Vec<Node>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_solid(&mut self, solid: Solid) -> SolidId {
+        self.solids.push(solid);
+        SolidId(self.solids.len() - 1)
+    }
+
+    /// Adds a node, `transform` is relative to the parent, or to the world for root nodes
+    pub fn add_node(
+        &mut self,
+        transform: Transform,
+        solid: Option<SolidId>,
+        parent: Option<NodeId>,
+    ) -> NodeId {
+        self.nodes.push(Node {
+            transform,
+            solid,
+            parent,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn solid(&self, id: SolidId) -> &Solid {
+        &self.solids[id.0]
+    }
+
+    pub fn solid_mut(&mut self, id: SolidId) -> &mut Solid {
+        &mut self.solids[id.0]
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0]
+    }
+
+    /// Model matrices of all nodes, indexed like the nodes
+    pub fn world_transforms(&self) -> Vec<Matrix4<f32>> {
+        let mut world: Vec<Matrix4<f32>> = Vec::with_capacity(self.nodes.len());
+
+        // Parents are always added before their children, so they are already resolved
+        for node in &self.nodes {
+            let parent = node.parent.map_or_else(Matrix4::identity, |p| world[p.0]);
+            world.push(parent * node.transform.matrix());
+        }
+
+        world
+    }
+
+    /// Every drawn instance of a solid together with its model matrix
+    pub fn instances(&self) -> Vec<(&Solid, Matrix4<f32>)> {
+        self.nodes
+            .iter()
+            .zip(self.world_transforms())
+            .filter_map(|(node, model)| Some((self.solid(node.solid?), model)))
+            .collect()
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    f32::consts::PI,
+    ops::{AddAssign, Index, IndexMut, MulAssign},
+    sync::Arc,
+};
+
+use cgmath::{
+    ElementWise, EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point3, SquareMatrix,
+    Vector2, Vector3, Vector4, Zero,
+};
+
+use crate::{
+    light::Light,
+    shadow::ShadowMap,
+    solid::{Filter, Material, Texture},
+};
+
+/// Maximum number of floats passed from the vertex shader to the fragment shader
+pub const MAX_VARYINGS: usize = 16;
+
+/// Outputs of the vertex shader, interpolated perspective-correctly across the triangle.
+///
+/// Shaders are free to choose the layout, the vector accessors just read consecutive floats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Varyings([f32; MAX_VARYINGS]);
+
+impl Varyings {
+    pub fn vec2(&self, i: usize) -> Vector2<f32> {
+        Vector2::new(self.0[i], self.0[i + 1])
+    }
+
+    pub fn vec3(&self, i: usize) -> Vector3<f32> {
+        Vector3::new(self.0[i], self.0[i + 1], self.0[i + 2])
+    }
+
+    pub fn vec4(&self, i: usize) -> Vector4<f32> {
+        Vector4::new(self.0[i], self.0[i + 1], self.0[i + 2], self.0[i + 3])
+    }
+
+    pub fn set_vec2(&mut self, i: usize, v: Vector2<f32>) {
+        let v: [f32; 2] = v.into();
+        self.0[i..i + 2].copy_from_slice(&v);
+    }
+
+    pub fn set_vec3(&mut self, i: usize, v: Vector3<f32>) {
+        let v: [f32; 3] = v.into();
+        self.0[i..i + 3].copy_from_slice(&v);
+    }
+
+    pub fn set_vec4(&mut self, i: usize, v: Vector4<f32>) {
+        let v: [f32; 4] = v.into();
+        self.0[i..i + 4].copy_from_slice(&v);
+    }
+}
+
+impl Default for Varyings {
+    fn default() -> Self {
+        Self([0.; MAX_VARYINGS])
+    }
+}
+
+impl Index<usize> for Varyings {
+    type Output = f32;
+
+    fn index(&self, i: usize) -> &f32 {
+        &self.0[i]
+    }
+}
+
+impl IndexMut<usize> for Varyings {
+    fn index_mut(&mut self, i: usize) -> &mut f32 {
+        &mut self.0[i]
+    }
+}
+
+impl MulAssign<f32> for Varyings {
+    fn mul_assign(&mut self, rhs: f32) {
+        self.0.iter_mut().for_each(|v| *v *= rhs);
+    }
+}
+
+impl AddAssign<Varyings> for Varyings {
+    fn add_assign(&mut self, rhs: Varyings) {
+        self.0.iter_mut().zip(rhs.0).for_each(|(v, r)| *v += r);
+    }
+}
+
+/// Values that are constant for the whole draw call
+#[derive(Debug, Clone)]
+pub struct Uniforms {
+    pub model: Matrix4<f32>,
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    /// Inverse transpose of the model-view matrix, transforms normals into view space
+    pub normal_matrix: Matrix3<f32>,
+    /// The model-view matrix mirrors the geometry, which flips the winding of its triangles
+    pub mirrored: bool,
+    /// Lights in view space
+    pub lights: Vec<Light>,
+    /// Shadow maps indexed like `lights`, lights without one aren't shadowed
+    pub shadow_maps: Vec<Option<Arc<ShadowMap>>>,
+    pub ambient_light: Vector3<f32>,
+}
+
+impl Uniforms {
+    /// `lights` are in world space
+    pub fn new(
+        model: Matrix4<f32>,
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        lights: &[Light],
+        ambient_light: Vector3<f32>,
+    ) -> Self {
+        let model_view = view * model;
+        let normal_matrix = Matrix3::from_cols(
+            model_view.x.truncate(),
+            model_view.y.truncate(),
+            model_view.z.truncate(),
+        )
+        .invert()
+        .unwrap_or_else(Matrix3::identity)
+        .transpose();
+
+        Self {
+            model,
+            view,
+            projection,
+            normal_matrix,
+            mirrored: model_view.determinant() < 0.,
+            lights: lights.iter().map(|l| l.transform(view)).collect(),
+            shadow_maps: Vec::new(),
+            ambient_light,
+        }
+    }
+
+    /// Fraction of light `light` reaching the view-space position `pos` with the normal `normal`
+    pub fn shadow(&self, light: usize, pos: Point3<f32>, normal: Vector3<f32>) -> f32 {
+        match self.shadow_maps.get(light) {
+            Some(Some(shadow_map)) => shadow_map.visibility(pos, normal),
+            _ => 1.,
+        }
+    }
+}
+
+/// Attributes of a single vertex of a mesh
+#[derive(Debug, Clone, Copy)]
+pub struct VertexInput {
+    pub position: Vector4<f32>,
+    pub normal: Vector3<f32>,
+    pub texcoords: Vector2<f32>,
+    /// See [`Mesh::tangents`](crate::Mesh::tangents)
+    pub tangent: Vector4<f32>,
+}
+
+/// Input of the fragment shader
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment {
+    pub x: usize,
+    pub y: usize,
+    /// Depth in normalized device coordinates
+    pub depth: f32,
+    pub varyings: Varyings,
+    /// Change of the varyings when moving one pixel to the right
+    pub ddx: Varyings,
+    /// Change of the varyings when moving one pixel down
+    pub ddy: Varyings,
+}
+
+impl Fragment {
+    /// Samples the RGBA texture with the texture coordinates stored at varying `i`
+    pub fn sample(&self, texture: &Texture, i: usize, filter: Filter) -> Vector4<f32> {
+        texture.sample(
+            self.varyings.vec2(i),
+            self.ddx.vec2(i),
+            self.ddy.vec2(i),
+            filter,
+        )
+    }
+}
+
+pub trait VertexShader: Sync {
+    /// Returns the clip-space position of the vertex and fills its varyings
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        input: &VertexInput,
+        varyings: &mut Varyings,
+    ) -> Vector4<f32>;
+}
+
+pub trait FragmentShader: Sync {
+    /// Returns the RGBA color of the fragment in the [0, 1] range, `None` discards the fragment.
+    ///
+    /// Alpha is only used by the alpha test and by the blending of transparent materials.
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        material: &Material,
+        fragment: &Fragment,
+    ) -> Option<Vector4<f32>>;
+}
+
+/// Default shader, the diffuse texture tinted by the diffuse color without any lighting
+pub struct TexturedShader;
+
+impl TexturedShader {
+    const TEXCOORDS: usize = 0;
+}
+
+impl VertexShader for TexturedShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        input: &VertexInput,
+        varyings: &mut Varyings,
+    ) -> Vector4<f32> {
+        varyings.set_vec2(Self::TEXCOORDS, input.texcoords);
+
+        uniforms.projection * uniforms.view * uniforms.model * input.position
+    }
+}
+
+impl FragmentShader for TexturedShader {
+    fn shade(
+        &self,
+        _uniforms: &Uniforms,
+        material: &Material,
+        fragment: &Fragment,
+    ) -> Option<Vector4<f32>> {
+        let texel = fragment.sample(&material.diffuse_texture, Self::TEXCOORDS, material.filter);
+        let col = texel.truncate().mul_element_wise(material.diffuse);
+
+        Some(col.extend(texel.w * material.dissolve))
+    }
+}
+
+/// Blinn-Phong lighting computed per pixel in view space
+pub struct BlinnPhongShader;
+
+impl BlinnPhongShader {
+    const TEXCOORDS: usize = 0;
+    const NORMAL: usize = 2;
+    const POSITION: usize = 5;
+    const TANGENT: usize = 8;
+}
+
+impl VertexShader for BlinnPhongShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        input: &VertexInput,
+        varyings: &mut Varyings,
+    ) -> Vector4<f32> {
+        let model_view = uniforms.view * uniforms.model;
+        let view_pos = model_view * input.position;
+
+        // Mirroring transforms flip the bitangent relative to the transformed normal and tangent
+        let tangent = (model_view * input.tangent.truncate().extend(0.)).truncate();
+        let handedness = if uniforms.mirrored {
+            -input.tangent.w
+        } else {
+            input.tangent.w
+        };
+
+        varyings.set_vec2(Self::TEXCOORDS, input.texcoords);
+        varyings.set_vec3(Self::NORMAL, uniforms.normal_matrix * input.normal);
+        varyings.set_vec3(Self::POSITION, view_pos.truncate());
+        varyings.set_vec4(Self::TANGENT, tangent.extend(handedness));
+
+        uniforms.projection * view_pos
+    }
+}
+
+impl BlinnPhongShader {
+    /// Returns the interpolated normal and the normal perturbed by the normal texture
+    fn normals(&self, material: &Material, fragment: &Fragment) -> (Vector3<f32>, Vector3<f32>) {
+        let vertex_normal = fragment.varyings.vec3(Self::NORMAL);
+        let vertex_normal = if vertex_normal.is_zero() {
+            vertex_normal
+        } else {
+            vertex_normal.normalize()
+        };
+
+        let normal = match &material.normal_texture {
+            Some(normal_texture) => perturb_normal(
+                vertex_normal,
+                fragment.varyings.vec4(Self::TANGENT),
+                fragment
+                    .sample(normal_texture, Self::TEXCOORDS, material.filter)
+                    .truncate(),
+            ),
+            None => vertex_normal,
+        };
+
+        (vertex_normal, normal)
+    }
+}
+
+impl FragmentShader for BlinnPhongShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        material: &Material,
+        fragment: &Fragment,
+    ) -> Option<Vector4<f32>> {
+        let pos = Point3::from_vec(fragment.varyings.vec3(Self::POSITION));
+
+        // The camera sits at the origin of the view space
+        let to_eye = -pos.to_vec().normalize();
+
+        let white = Vector3::new(1., 1., 1.);
+        let sample = |tex: &Texture| fragment.sample(tex, Self::TEXCOORDS, material.filter);
+        let sample_or = |tex: &Option<Texture>, default: Vector3<f32>| {
+            tex.as_ref().map_or(default, |t| sample(t).truncate())
+        };
+
+        let (vertex_normal, normal) = self.normals(material, fragment);
+
+        let diffuse_texel = sample(&material.diffuse_texture);
+        let albedo = diffuse_texel.truncate();
+        let dissolve = sample_or(&material.dissolve_texture, white).x;
+        let alpha = diffuse_texel.w * material.dissolve * dissolve;
+        let ambient = sample_or(&material.ambient_texture, albedo);
+        let specular_col = sample_or(&material.specular_texture, white);
+        let shininess = material.shininess * sample_or(&material.shininess_texture, white).x;
+
+        let mut col = material
+            .ambient
+            .mul_element_wise(uniforms.ambient_light)
+            .mul_element_wise(ambient);
+
+        for (i, light) in uniforms.lights.iter().enumerate() {
+            let (to_light, radiance) = light.incidence(pos);
+
+            let diffuse = normal.dot(to_light).max(0.);
+            if diffuse <= 0. {
+                continue;
+            }
+
+            // Shadows follow the geometry, the normal map only changes the shading
+            let radiance = radiance * uniforms.shadow(i, pos, vertex_normal);
+
+            let half = (to_light + to_eye).normalize();
+            let specular = normal.dot(half).max(0.).powf(shininess);
+
+            let reflected = material.diffuse.mul_element_wise(albedo) * diffuse
+                + material.specular.mul_element_wise(specular_col) * specular;
+            col += reflected.mul_element_wise(radiance);
+        }
+
+        Some(col.extend(alpha))
+    }
+}
+
+/// Cook-Torrance BRDF with the GGX distribution evaluated per pixel
+/// with the [`Pbr`](crate::Pbr) parameters, the vertex shader is shared with [`BlinnPhongShader`]
+pub struct PbrShader;
+
+impl PbrShader {
+    /// Keeps the highlights of perfectly smooth surfaces from collapsing into a single point
+    const MIN_ROUGHNESS: f32 = 0.045;
+}
+
+impl VertexShader for PbrShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        input: &VertexInput,
+        varyings: &mut Varyings,
+    ) -> Vector4<f32> {
+        VertexShader::shade(&BlinnPhongShader, uniforms, input, varyings)
+    }
+}
+
+impl FragmentShader for PbrShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        material: &Material,
+        fragment: &Fragment,
+    ) -> Option<Vector4<f32>> {
+        let pbr = &material.pbr;
+        let pos = Point3::from_vec(fragment.varyings.vec3(BlinnPhongShader::POSITION));
+        let (vertex_normal, normal) = BlinnPhongShader.normals(material, fragment);
+        let to_eye = -pos.to_vec().normalize();
+
+        let sample =
+            |tex: &Texture| fragment.sample(tex, BlinnPhongShader::TEXCOORDS, material.filter);
+        let sample_or = |tex: &Option<Texture>, default: Vector3<f32>| {
+            tex.as_ref().map_or(default, |t| sample(t).truncate())
+        };
+
+        let white = Vector3::new(1., 1., 1.);
+        let base_texel = sample(&material.diffuse_texture);
+        let base_color = pbr.base_color.mul_element_wise(base_texel.truncate());
+        let alpha =
+            base_texel.w * material.dissolve * sample_or(&material.dissolve_texture, white).x;
+
+        let metallic_roughness = sample_or(&pbr.metallic_roughness_texture, white);
+        let metallic = (pbr.metallic * metallic_roughness.z).clamp(0., 1.);
+        let roughness = (pbr.roughness * metallic_roughness.y).clamp(Self::MIN_ROUGHNESS, 1.);
+        let occlusion =
+            1. + pbr.occlusion_strength * (sample_or(&pbr.occlusion_texture, white).x - 1.);
+        let emissive = pbr
+            .emissive
+            .mul_element_wise(sample_or(&pbr.emissive_texture, white));
+
+        // Dielectrics reflect about 4% of the light head-on, metals reflect their base color
+        let dielectric = Vector3::new(0.04, 0.04, 0.04);
+        let f0 = dielectric * (1. - metallic) + base_color * metallic;
+        let diffuse_color = base_color * (1. - metallic);
+
+        let alpha_sq = roughness.powi(4);
+        let n_dot_v = normal.dot(to_eye).max(1e-4);
+
+        let mut col = uniforms.ambient_light.mul_element_wise(base_color) * occlusion + emissive;
+
+        for (i, light) in uniforms.lights.iter().enumerate() {
+            let (to_light, radiance) = light.incidence(pos);
+
+            let n_dot_l = normal.dot(to_light);
+            if n_dot_l <= 0. {
+                continue;
+            }
+
+            let radiance = radiance * uniforms.shadow(i, pos, vertex_normal);
+
+            let half = (to_light + to_eye).normalize();
+            let n_dot_h = normal.dot(half).max(0.);
+            let v_dot_h = to_eye.dot(half).max(0.);
+
+            let d = alpha_sq / (PI * (n_dot_h * n_dot_h * (alpha_sq - 1.) + 1.).powi(2));
+
+            // Height-correlated Smith visibility, includes the denominator of the BRDF
+            let smith = |n_dot_x: f32| (n_dot_x * n_dot_x * (1. - alpha_sq) + alpha_sq).sqrt();
+            let vis = 0.5 / (n_dot_l * smith(n_dot_v) + n_dot_v * smith(n_dot_l));
+
+            let fresnel = f0 + (white - f0) * (1. - v_dot_h).powi(5);
+
+            let diffuse = (white - fresnel).mul_element_wise(diffuse_color) / PI;
+            let specular = fresnel * (d * vis);
+
+            // Light colors are the radiance reflected by a white diffuse surface facing the light,
+            // like in the Blinn-Phong shader, so the BRDF is scaled by pi
+            col += (diffuse + specular).mul_element_wise(radiance) * (PI * n_dot_l);
+        }
+
+        Some(col.extend(alpha))
+    }
+}
+
+/// Rotates a texel of a tangent-space normal map into the space of `normal` and `tangent`,
+/// which don't have to be normalized or orthogonal after interpolation
+pub fn perturb_normal(
+    normal: Vector3<f32>,
+    tangent: Vector4<f32>,
+    texel: Vector3<f32>,
+) -> Vector3<f32> {
+    if normal.is_zero() {
+        return normal;
+    }
+    let normal = normal.normalize();
+
+    // Gram-Schmidt, the interpolated tangent drifts away from the normal
+    let tangent_dir = tangent.truncate() - normal * normal.dot(tangent.truncate());
+    if tangent_dir.is_zero() {
+        return normal;
+    }
+    let tangent_dir = tangent_dir.normalize();
+    let handedness = if tangent.w < 0. { -1. } else { 1. };
+    let bitangent = normal.cross(tangent_dir) * handedness;
+
+    let texel = texel * 2. - Vector3::new(1., 1., 1.);
+    let perturbed = tangent_dir * texel.x + bitangent * texel.y + normal * texel.z;
+    if perturbed.is_zero() {
+        normal
+    } else {
+        perturbed.normalize()
+    }
+}
@@ -0,0 +1,393 @@
+use std::f32::consts::FRAC_PI_2;
+
+use cgmath::{
+    EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Transform, Vector3, Vector4,
+};
+
+use crate::{
+    light::Light,
+    raster::Raster,
+    shader::{Fragment, FragmentShader, Uniforms, Varyings, VertexInput, VertexShader},
+    solid::{Material, Solid},
+};
+
+/// Near plane of the perspective projections of the lights
+const LIGHT_NEAR: f32 = 0.05;
+
+/// View directions and up vectors of the faces of a cube map, in the order +X, -X, +Y, -Y, +Z, -Z
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1., 0., 0.], [0., -1., 0.]),
+    ([-1., 0., 0.], [0., -1., 0.]),
+    ([0., 1., 0.], [0., 0., 1.]),
+    ([0., -1., 0.], [0., 0., -1.]),
+    ([0., 0., 1.], [0., -1., 0.]),
+    ([0., 0., -1.], [0., -1., 0.]),
+];
+
+/// Settings of the shadow maps rendered for the lights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSettings {
+    /// Width and height of the shadow maps in texels, point lights render six of them
+    pub resolution: usize,
+    /// Distance in world units a surface has to be behind an occluder to be shadowed,
+    /// hides the self-shadowing caused by the limited resolution of the map
+    pub bias: f32,
+    /// Distance in world units the looked up position is moved along the surface normal,
+    /// helps with surfaces at grazing angles to the light
+    pub normal_offset: f32,
+    /// The PCF kernel covers `(2 * pcf_radius + 1)^2` texels, 0 gives hard edges
+    pub pcf_radius: usize,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 1024,
+            bias: 0.05,
+            normal_offset: 0.03,
+            pcf_radius: 1,
+        }
+    }
+}
+
+/// Depth of the scene as seen from a light
+#[derive(Debug)]
+pub struct ShadowMap {
+    /// A single face for directional and spot lights, a cube map in the order of [`CUBE_FACES`]
+    /// for point lights
+    faces: Vec<ShadowFace>,
+    /// World-space position of a point light
+    cube_center: Option<Point3<f32>>,
+    /// Transforms from the view space of the camera into the world space
+    inv_view: Matrix4<f32>,
+    settings: ShadowSettings,
+}
+
+impl ShadowMap {
+    /// `faces` are rendered with the views returned by [`light_views`]
+    pub(crate) fn new(
+        light: &Light,
+        faces: Vec<ShadowFace>,
+        inv_view: Matrix4<f32>,
+        settings: ShadowSettings,
+    ) -> Self {
+        let cube_center = match *light {
+            Light::Point { position, .. } => Some(position),
+            _ => None,
+        };
+
+        Self {
+            faces,
+            cube_center,
+            inv_view,
+            settings,
+        }
+    }
+
+    /// Fraction of the light reaching `pos`, both `pos` and `normal` are in view space
+    pub fn visibility(&self, pos: Point3<f32>, normal: Vector3<f32>) -> f32 {
+        let pos = pos + normal * self.settings.normal_offset;
+        let pos = self.inv_view.transform_point(pos);
+
+        match self.cube_center {
+            Some(center) => self.cube_visibility(center, pos),
+            None => self.face_visibility(pos),
+        }
+    }
+
+    fn face_visibility(&self, pos: Point3<f32>) -> f32 {
+        let face = &self.faces[0];
+        let Some((x, y)) = face.texel_coords(pos) else {
+            return 1.;
+        };
+        let depth = face.depth(pos) - self.settings.bias;
+
+        let radius = self.settings.pcf_radius as i64;
+        let mut lit = 0.;
+        for ty in -radius..=radius {
+            for tx in -radius..=radius {
+                lit += face.lit(x + tx as f32, y + ty as f32, depth);
+            }
+        }
+
+        lit / (2 * radius + 1).pow(2) as f32
+    }
+
+    /// The taps are spread around the direction from the light, each of them looks into its own
+    /// face, so the filtering doesn't stop at the edges of the faces
+    fn cube_visibility(&self, center: Point3<f32>, pos: Point3<f32>) -> f32 {
+        let dir = pos - center;
+        let major = dir.x.abs().max(dir.y.abs()).max(dir.z.abs());
+        if major <= 0. {
+            return 1.;
+        }
+
+        // The faces span -1..1 at the distance of 1 along their axis
+        let dir = dir / major;
+        let texel = 2. / self.faces[0].size as f32;
+        let u = up_vector(dir).cross(dir).normalize() * texel;
+        let v = dir.cross(u).normalize() * texel;
+
+        let radius = self.settings.pcf_radius as i64;
+        let mut lit = 0.;
+        for ty in -radius..=radius {
+            for tx in -radius..=radius {
+                let tap = dir + u * tx as f32 + v * ty as f32;
+                let face = &self.faces[cube_face(tap)];
+
+                lit += match face.texel_coords(center + tap) {
+                    Some((x, y)) => face.lit(x, y, face.depth(pos) - self.settings.bias),
+                    None => 1.,
+                };
+            }
+        }
+
+        lit / (2 * radius + 1).pow(2) as f32
+    }
+}
+
+/// Index of the face of a cube map `dir` points into
+fn cube_face(dir: Vector3<f32>) -> usize {
+    let abs = dir.map(f32::abs);
+    if abs.x >= abs.y && abs.x >= abs.z {
+        if dir.x > 0. {
+            0
+        } else {
+            1
+        }
+    } else if abs.y >= abs.z {
+        if dir.y > 0. {
+            2
+        } else {
+            3
+        }
+    } else if dir.z > 0. {
+        4
+    } else {
+        5
+    }
+}
+
+/// Depth buffer rendered with a single view of the light
+#[derive(Debug)]
+pub(crate) struct ShadowFace {
+    /// Distances of the closest surfaces along the axis of the view
+    depths: Vec<f32>,
+    size: usize,
+    /// Transforms from the world space into the view space of the face
+    light_view: Matrix4<f32>,
+    projection: Matrix4<f32>,
+}
+
+impl ShadowFace {
+    /// Takes the depth buffer of a `raster` rendered with `light_view` and `projection`
+    pub(crate) fn new(raster: &Raster, light_view: Matrix4<f32>, projection: Matrix4<f32>) -> Self {
+        let inv_projection = projection.invert().unwrap_or_else(Matrix4::identity);
+
+        // Distances are linear for both projections, so the bias works the same everywhere
+        let depths = raster
+            .z_buf()
+            .iter()
+            .map(|z| {
+                let pos = inv_projection * Vector4::new(0., 0., *z, 1.);
+                -pos.z / pos.w
+            })
+            .collect();
+
+        Self {
+            depths,
+            size: raster.width(),
+            light_view,
+            projection,
+        }
+    }
+
+    /// Distance of the world-space position `pos` along the axis of the view
+    fn depth(&self, pos: Point3<f32>) -> f32 {
+        -self.light_view.transform_point(pos).z
+    }
+
+    /// Continuous texel coordinates of the world-space position `pos`, `None` behind the light
+    fn texel_coords(&self, pos: Point3<f32>) -> Option<(f32, f32)> {
+        let clip = self.projection * self.light_view * pos.to_homogeneous();
+        if clip.w <= 0. {
+            return None;
+        }
+
+        let size = self.size as f32;
+        let x = 0.5 * size * (clip.x / clip.w + 1.) - 0.5;
+        let y = 0.5 * size * (1. - clip.y / clip.w) - 0.5;
+
+        Some((x, y))
+    }
+
+    /// Compares `depth` with the four closest texels and blends the results bilinearly
+    fn lit(&self, x: f32, y: f32, depth: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top =
+            self.texel_lit(x0, y0, depth) * (1. - fx) + self.texel_lit(x0 + 1, y0, depth) * fx;
+        let bottom = self.texel_lit(x0, y0 + 1, depth) * (1. - fx)
+            + self.texel_lit(x0 + 1, y0 + 1, depth) * fx;
+
+        top * (1. - fy) + bottom * fy
+    }
+
+    /// Texels outside of the map don't shadow anything
+    fn texel_lit(&self, x: i64, y: i64, depth: f32) -> f32 {
+        let size = self.size as i64;
+        if !(0..size).contains(&x) || !(0..size).contains(&y) {
+            return 1.;
+        }
+
+        if depth <= self.depths[(y * size + x) as usize] {
+            1.
+        } else {
+            0.
+        }
+    }
+}
+
+/// View and projection matrices of the shadow map faces of `light`,
+/// directional lights cover the whole `bounds` of the scene
+pub(crate) fn light_views(
+    light: &Light,
+    bounds: Option<(Point3<f32>, Point3<f32>)>,
+) -> Vec<(Matrix4<f32>, Matrix4<f32>)> {
+    match *light {
+        Light::Directional { direction, .. } => {
+            let Some((min, max)) = bounds else {
+                return Vec::new();
+            };
+            let center = min.midpoint(max);
+            let radius = (max - min).magnitude() / 2.;
+            if radius <= 0. {
+                return Vec::new();
+            }
+
+            let direction = direction.normalize();
+            let eye = center - direction * radius;
+            let view = Matrix4::look_to_rh(eye, direction, up_vector(direction));
+            let projection = cgmath::ortho(-radius, radius, -radius, radius, 0., 2. * radius);
+
+            vec![(view, projection)]
+        }
+        Light::Spot {
+            position,
+            direction,
+            range,
+            outer_angle,
+            ..
+        } => {
+            let direction = direction.normalize();
+            let view = Matrix4::look_to_rh(position, direction, up_vector(direction));
+            let fov = Rad((2. * outer_angle.0).min(std::f32::consts::PI * 0.99));
+            let projection = cgmath::perspective(fov, 1., LIGHT_NEAR, range.max(LIGHT_NEAR * 2.));
+
+            vec![(view, projection)]
+        }
+        Light::Point {
+            position, range, ..
+        } => {
+            let projection =
+                cgmath::perspective(Rad(FRAC_PI_2), 1., LIGHT_NEAR, range.max(LIGHT_NEAR * 2.));
+
+            CUBE_FACES
+                .iter()
+                .map(|(direction, up)| {
+                    let view = Matrix4::look_to_rh(position, (*direction).into(), (*up).into());
+                    (view, projection)
+                })
+                .collect()
+        }
+    }
+}
+
+/// Any up vector that isn't parallel to `direction`
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+/// World-space bounding box of all instances
+pub(crate) fn bounds(instances: &[(&Solid, Matrix4<f32>)]) -> Option<(Point3<f32>, Point3<f32>)> {
+    let mut bounds: Option<(Point3<f32>, Point3<f32>)> = None;
+
+    for (solid, model) in instances {
+        for mesh in &solid.meshes {
+            let Some(first) = mesh.positions.first() else {
+                continue;
+            };
+
+            // Transforming the corners of the local box is cheaper than transforming every vertex
+            let first = Point3::from_homogeneous(*first);
+            let (min, max) = mesh.positions.iter().fold((first, first), |(min, max), p| {
+                let p = Point3::from_homogeneous(*p);
+                (
+                    Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                    Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+                )
+            });
+
+            for corner in 0..8 {
+                let corner = Point3::new(
+                    if corner & 1 == 0 { min.x } else { max.x },
+                    if corner & 2 == 0 { min.y } else { max.y },
+                    if corner & 4 == 0 { min.z } else { max.z },
+                );
+                let p = model.transform_point(corner);
+
+                let (min, max) = bounds.get_or_insert((p, p));
+                *min = Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                *max = Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+            }
+        }
+    }
+
+    bounds
+}
+
+/// Depth-only shader of the shadow pass, only alpha-tested materials are sampled
+pub(crate) struct ShadowShader;
+
+impl ShadowShader {
+    const TEXCOORDS: usize = 0;
+}
+
+impl VertexShader for ShadowShader {
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        input: &VertexInput,
+        varyings: &mut Varyings,
+    ) -> Vector4<f32> {
+        varyings.set_vec2(Self::TEXCOORDS, input.texcoords);
+
+        uniforms.projection * uniforms.view * uniforms.model * input.position
+    }
+}
+
+impl FragmentShader for ShadowShader {
+    fn shade(
+        &self,
+        _uniforms: &Uniforms,
+        material: &Material,
+        fragment: &Fragment,
+    ) -> Option<Vector4<f32>> {
+        let alpha = match material.alpha_cutoff {
+            Some(_) => {
+                let texel =
+                    fragment.sample(&material.diffuse_texture, Self::TEXCOORDS, material.filter);
+                texel.w * material.dissolve
+            }
+            None => 1.,
+        };
+
+        Some(Vector4::new(0., 0., 0., alpha))
+    }
+}
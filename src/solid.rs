@@ -1,6 +1,10 @@
-use cgmath::{Point3, Vector2, Vector3, Vector4};
+use std::collections::HashMap;
+
+use cgmath::{ElementWise, InnerSpace, Point3, Vector2, Vector3, Vector4, Zero};
 use image::DynamicImage;
 
+use crate::raster::{decode_srgb, encode_srgb, Blend};
+
 pub struct Solid {
     pub meshes: Vec<Mesh>,
 }
@@ -21,10 +25,15 @@ pub struct Mesh {
     pub texcoords: Vec<Vector2<f32>>,
     pub texcoord_indices: Vec<[u32; 3]>,
 
+    /// The w component is the handedness, the bitangent is `w * normal.cross(tangent)`
+    pub tangents: Vec<Vector4<f32>>,
+    pub tangent_indices: Vec<[u32; 3]>,
+
     pub material: Material,
 }
 
 impl Mesh {
+    /// Tangents are generated with MikkTSpace
     pub fn new(
         positions: Vec<f32>,
         pos_indices: Vec<u32>,
@@ -34,20 +43,55 @@ impl Mesh {
         texcoord_indices: Vec<u32>,
         material: Material,
     ) -> Self {
-        let positions = positions
+        let mut mesh = Self::without_tangents(
+            positions,
+            pos_indices,
+            normals,
+            normal_indices,
+            texcoords,
+            texcoord_indices,
+            material,
+        );
+        mesh.generate_tangents();
+
+        mesh
+    }
+
+    /// Leaves the tangents empty, they have to be filled before the mesh is rendered
+    pub(crate) fn without_tangents(
+        positions: Vec<f32>,
+        pos_indices: Vec<u32>,
+        normals: Vec<f32>,
+        normal_indices: Vec<u32>,
+        texcoords: Vec<f32>,
+        texcoord_indices: Vec<u32>,
+        material: Material,
+    ) -> Self {
+        let positions: Vec<_> = positions
             .array_chunks()
             .map(|c| Point3::from(*c).to_homogeneous())
             .collect();
-        let pos_indices = pos_indices.array_chunks().map(|c| *c).collect();
+        let pos_indices: Vec<_> = pos_indices.array_chunks().map(|c| *c).collect();
 
-        let normals = normals.array_chunks().map(|c| Vector3::from(*c)).collect();
-        let normal_indices = normal_indices.array_chunks().map(|c| *c).collect();
+        let (normals, normal_indices) = if normal_indices.is_empty() {
+            Self::face_normals(&positions, &pos_indices)
+        } else {
+            let normals = normals.array_chunks().map(|c| Vector3::from(*c)).collect();
+            let normal_indices = normal_indices.array_chunks().map(|c| *c).collect();
+            (normals, normal_indices)
+        };
 
-        let texcoords = texcoords
-            .array_chunks()
-            .map(|c| Vector2::from(*c))
-            .collect();
-        let texcoord_indices = texcoord_indices.array_chunks().map(|c| *c).collect();
+        // Meshes without texture coordinates sample a single texel of the texture
+        let (texcoords, texcoord_indices) = if texcoord_indices.is_empty() {
+            (vec![Vector2::zero()], vec![[0; 3]; pos_indices.len()])
+        } else {
+            let texcoords = texcoords
+                .array_chunks()
+                .map(|c| Vector2::from(*c))
+                .collect();
+            let texcoord_indices = texcoord_indices.array_chunks().map(|c| *c).collect();
+            (texcoords, texcoord_indices)
+        };
 
         Self {
             positions,
@@ -56,56 +100,563 @@ impl Mesh {
             normal_indices,
             texcoords,
             texcoord_indices,
+            tangents: Vec::new(),
+            tangent_indices: Vec::new(),
             material,
         }
     }
+
+    /// Generates the tangents with MikkTSpace, which is what normal maps are usually baked with
+    pub(crate) fn generate_tangents(&mut self) {
+        let mut geometry = TangentGeometry {
+            mesh: self,
+            tangents: vec![[0.; 4]; self.pos_indices.len() * 3],
+        };
+
+        let mut corner_tangents = if mikktspace::generate_tangents(&mut geometry) {
+            geometry.tangents
+        } else {
+            Vec::new()
+        };
+
+        // MikkTSpace gives up on meshes without any usable triangles
+        if corner_tangents.is_empty() {
+            corner_tangents = self
+                .normal_indices
+                .iter()
+                .flatten()
+                .map(|i| {
+                    Self::perpendicular(self.normals[*i as usize])
+                        .extend(1.)
+                        .into()
+                })
+                .collect();
+        }
+
+        // Corners with equal tangents share a single one
+        let mut unique: HashMap<[u32; 4], u32> = HashMap::new();
+        let mut tangents = Vec::new();
+        let indices: Vec<u32> = corner_tangents
+            .iter()
+            .map(|t| {
+                *unique.entry(t.map(f32::to_bits)).or_insert_with(|| {
+                    tangents.push(Vector4::from(*t));
+                    tangents.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        self.tangents = tangents;
+        self.tangent_indices = indices.array_chunks().map(|c| *c).collect();
+    }
+
+    /// Any unit vector perpendicular to `normal`
+    fn perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+        let axis = if normal.x.abs() < 0.9 {
+            Vector3::unit_x()
+        } else {
+            Vector3::unit_y()
+        };
+
+        (axis - normal * normal.dot(axis)).normalize()
+    }
+
+    /// Generates a flat normal for every triangle, degenerate triangles get a zero normal
+    fn face_normals(
+        positions: &[Vector4<f32>],
+        pos_indices: &[[u32; 3]],
+    ) -> (Vec<Vector3<f32>>, Vec<[u32; 3]>) {
+        let normals = pos_indices
+            .iter()
+            .map(|[a, b, c]| {
+                let a = positions[*a as usize].truncate();
+                let b = positions[*b as usize].truncate();
+                let c = positions[*c as usize].truncate();
+
+                let normal = (b - a).cross(c - a);
+                if normal.is_zero() {
+                    normal
+                } else {
+                    normal.normalize()
+                }
+            })
+            .collect();
+
+        let normal_indices = (0..pos_indices.len() as u32).map(|i| [i; 3]).collect();
+
+        (normals, normal_indices)
+    }
+}
+
+/// Triangles of a mesh as seen by MikkTSpace, the tangents are stored per corner
+struct TangentGeometry<'m> {
+    mesh: &'m Mesh,
+    tangents: Vec<[f32; 4]>,
+}
+
+impl mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.mesh.pos_indices.len()
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        let index = self.mesh.pos_indices[face][vert] as usize;
+        self.mesh.positions[index].truncate().into()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        let index = self.mesh.normal_indices[face][vert] as usize;
+        self.mesh.normals[index].into()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let index = self.mesh.texcoord_indices[face][vert] as usize;
+        self.mesh.texcoords[index].into()
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = tangent;
+    }
 }
 
+/// Material as described by the MTL format
 pub struct Material {
-    // ambient
-    // diffuse
-    // specular
-    // shininess
+    /// Ka
+    pub ambient: Vector3<f32>,
+    /// Kd, multiplies the diffuse texture
+    pub diffuse: Vector3<f32>,
+    /// Ks
+    pub specular: Vector3<f32>,
+    /// Ns
+    pub shininess: f32,
+    /// d, 1 is fully opaque
+    pub dissolve: f32,
+    /// Fragments with a lower alpha are discarded
+    pub alpha_cutoff: Option<f32>,
+    /// How fragments are combined with the raster, transparent materials are drawn last
+    pub blend: Blend,
+    /// Filtering of all textures of the material
+    pub filter: Filter,
+    /// Faces are never culled, regardless of the cull mode of the renderer
+    pub double_sided: bool,
+
+    /// map_Ka
+    pub ambient_texture: Option<Texture>,
+    /// map_Kd, white if the material doesn't have one
     pub diffuse_texture: Texture,
+    /// map_Ks
+    pub specular_texture: Option<Texture>,
+    /// map_Bump / norm
+    pub normal_texture: Option<Texture>,
+    /// map_Ns, only the red channel is used
+    pub shininess_texture: Option<Texture>,
+    /// map_d, only the red channel is used
+    pub dissolve_texture: Option<Texture>,
+
+    /// Parameters of the physically based shading
+    pub pbr: Pbr,
 }
 
-impl Material {
-    pub fn new(diffuse_texture: DynamicImage) -> Self {
-        let diffuse_texture = diffuse_texture.into_rgba8();
-        let flat = diffuse_texture
-            .as_raw()
-            .array_chunks::<4>()
-            .map(|c| Vector3::new(c[0], c[1], c[2]))
-            .collect();
+/// Metallic-roughness material model of glTF.
+///
+/// The base color texture is the diffuse texture of the [`Material`],
+/// the alpha comes from its dissolve.
+pub struct Pbr {
+    /// Multiplies the diffuse texture
+    pub base_color: Vector3<f32>,
+    /// 0 for dielectrics, 1 for metals
+    pub metallic: f32,
+    /// Perceptual roughness, squared before it is used by the GGX distribution
+    pub roughness: f32,
+    /// How much the occlusion texture darkens the ambient light
+    pub occlusion_strength: f32,
+    /// Light emitted by the surface itself
+    pub emissive: Vector3<f32>,
+
+    /// Roughness in the green channel and metalness in the blue channel, multiplies the factors
+    pub metallic_roughness_texture: Option<Texture>,
+    /// Ambient occlusion in the red channel
+    pub occlusion_texture: Option<Texture>,
+    /// Multiplies the emissive factor
+    pub emissive_texture: Option<Texture>,
+}
+
+impl Default for Pbr {
+    fn default() -> Self {
+        Self {
+            base_color: Vector3::new(1., 1., 1.),
+            metallic: 0.,
+            roughness: 1.,
+            occlusion_strength: 1.,
+            emissive: Vector3::new(0., 0., 0.),
+            metallic_roughness_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
 
+impl Default for Material {
+    fn default() -> Self {
         Self {
-            diffuse_texture: Texture::new(flat, diffuse_texture.width(), diffuse_texture.height()),
+            ambient: Vector3::new(1., 1., 1.),
+            diffuse: Vector3::new(1., 1., 1.),
+            specular: Vector3::new(0., 0., 0.),
+            shininess: 1.,
+            dissolve: 1.,
+            alpha_cutoff: None,
+            blend: Blend::Opaque,
+            filter: Filter::Trilinear,
+            double_sided: false,
+            ambient_texture: None,
+            diffuse_texture: Texture::default(),
+            specular_texture: None,
+            normal_texture: None,
+            shininess_texture: None,
+            dissolve_texture: None,
+            pbr: Pbr::default(),
         }
     }
 }
 
+/// How textures are filtered when they are sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// Closest texel of the full resolution image
+    Nearest,
+    /// Bilinear interpolation in the closest mip level
+    Bilinear,
+    /// Bilinear interpolation in the two closest mip levels, blended by the level of detail
+    Trilinear,
+    /// Averages up to `max_anisotropy` trilinear samples along the longer axis of the pixel
+    /// footprint, which keeps surfaces viewed at grazing angles sharp
+    Anisotropic { max_anisotropy: u32 },
+}
+
+/// How texel coordinates outside of the texture are mapped back into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+    /// Texels outside of the texture have the border color of the texture
+    Border,
+}
+
+impl Wrap {
+    /// Returns the texel coordinate inside of `0..size`, `None` selects the border color
+    fn apply(self, coord: i64, size: u32) -> Option<i64> {
+        let size = size as i64;
+
+        match self {
+            Wrap::Repeat => Some(coord.rem_euclid(size)),
+            Wrap::MirroredRepeat => {
+                let coord = coord.rem_euclid(2 * size);
+                if coord < size {
+                    Some(coord)
+                } else {
+                    Some(2 * size - 1 - coord)
+                }
+            }
+            Wrap::ClampToEdge => Some(coord.clamp(0, size - 1)),
+            Wrap::Border => (0..size).contains(&coord).then_some(coord),
+        }
+    }
+}
+
+/// How the color channels of a texture are encoded, alpha is always linear
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Data like normals or roughness, used as is
+    Linear,
+    /// Colors meant to be displayed, they are decoded to linear values when sampled
+    Srgb,
+}
+
+impl ColorSpace {
+    /// Converts an encoded texel to linear RGBA in the [0, 1] range
+    fn decode(self, col: Vector4<u8>) -> Vector4<f32> {
+        match self {
+            ColorSpace::Linear => col.cast::<f32>().unwrap() / 255.,
+            ColorSpace::Srgb => col.truncate().map(decode_srgb).extend(col.w as f32 / 255.),
+        }
+    }
+
+    fn encode(self, col: Vector4<f32>) -> Vector4<u8> {
+        let to_u8 = |c: f32| (c.clamp(0., 1.) * 255.).round() as u8;
+        match self {
+            ColorSpace::Linear => col.map(to_u8),
+            ColorSpace::Srgb => col.truncate().map(encode_srgb).extend(to_u8(col.w)),
+        }
+    }
+}
+
+/// RGBA texture, the default texture is a single opaque white pixel
 pub struct Texture {
-    pixels: Vec<Vector3<u8>>,
+    /// Mip chain, each level has half the resolution of the previous one
+    levels: Vec<MipLevel>,
+    color_space: ColorSpace,
     pub width: u32,
     pub height: u32,
+    /// Addressing mode along the u axis, repeats by default
+    pub wrap_u: Wrap,
+    /// Addressing mode along the v axis, repeats by default
+    pub wrap_v: Wrap,
+    /// RGBA color in the [0, 1] range used by [`Wrap::Border`]
+    pub border_color: Vector4<f32>,
 }
 
 impl Texture {
-    pub fn new(pixels: Vec<Vector3<u8>>, width: u32, height: u32) -> Self {
-        Self {
+    /// Builds the whole mip chain of the image, the texels are averaged in linear space
+    pub fn new(pixels: Vec<Vector4<u8>>, width: u32, height: u32, color_space: ColorSpace) -> Self {
+        let mut levels = vec![MipLevel {
             pixels,
             width,
             height,
+        }];
+
+        loop {
+            let last = &levels[levels.len() - 1];
+            if last.width <= 1 && last.height <= 1 {
+                break;
+            }
+
+            let next = last.downsample(color_space);
+            levels.push(next);
+        }
+
+        Self {
+            levels,
+            color_space,
+            width,
+            height,
+            wrap_u: Wrap::Repeat,
+            wrap_v: Wrap::Repeat,
+            border_color: Vector4::new(0., 0., 0., 0.),
         }
     }
 
-    pub fn get_pixel(&self, x: usize, y: usize) -> Vector3<u8> {
-        let index = y * self.width as usize + x;
+    pub fn from_image(img: DynamicImage, color_space: ColorSpace) -> Self {
+        let img = img.into_rgba8();
+        let flat = img
+            .as_raw()
+            .array_chunks::<4>()
+            .map(|c| Vector4::from(*c))
+            .collect();
+
+        Self::new(flat, img.width(), img.height(), color_space)
+    }
 
-        if index >= self.pixels.len() {
-            return Vector3::new(255, 255, 255);
+    /// Returns the RGBA color in the [0, 1] range.
+    ///
+    /// `ddx` and `ddy` are the screen-space derivatives of `texcoords`, they select the mip level.
+    pub fn sample(
+        &self,
+        texcoords: Vector2<f32>,
+        ddx: Vector2<f32>,
+        ddy: Vector2<f32>,
+        filter: Filter,
+    ) -> Vector4<f32> {
+        let max_level = (self.levels.len() - 1) as f32;
+
+        match filter {
+            Filter::Nearest => self.nearest(0, texcoords),
+            Filter::Bilinear => {
+                let level = self.lod(ddx, ddy).round().clamp(0., max_level);
+                self.bilinear(level as usize, texcoords)
+            }
+            Filter::Trilinear => self.trilinear(self.lod(ddx, ddy), texcoords),
+            Filter::Anisotropic { max_anisotropy } => {
+                let size = Vector2::new(self.width as f32, self.height as f32);
+                let len_x = ddx.mul_element_wise(size).magnitude();
+                let len_y = ddy.mul_element_wise(size).magnitude();
+
+                let (major, major_len, minor_len) = if len_x >= len_y {
+                    (ddx, len_x, len_y)
+                } else {
+                    (ddy, len_y, len_x)
+                };
+
+                let ratio = major_len / minor_len.max(f32::MIN_POSITIVE);
+                let ratio = ratio.min(max_anisotropy as f32);
+                let samples = if ratio > 1. { ratio.ceil() as u32 } else { 1 };
+
+                // Each sample covers an equal part of the major axis
+                let lod = (major_len / samples as f32).log2();
+                let mut col = Vector4::new(0., 0., 0., 0.);
+                for i in 0..samples {
+                    let offset = (i as f32 + 0.5) / samples as f32 - 0.5;
+                    col += self.trilinear(lod, texcoords + major * offset);
+                }
+
+                col / samples as f32
+            }
         }
+    }
+
+    fn trilinear(&self, lod: f32, texcoords: Vector2<f32>) -> Vector4<f32> {
+        let max_level = (self.levels.len() - 1) as f32;
+        let lod = lod.clamp(0., max_level);
+        let level = lod.floor();
+        let t = lod - level;
+
+        let col = self.bilinear(level as usize, texcoords);
+        if t == 0. {
+            return col;
+        }
+
+        let next = self.bilinear(level as usize + 1, texcoords);
+        col * (1. - t) + next * t
+    }
+
+    /// Level of detail, the base 2 logarithm of the texel footprint of a pixel
+    fn lod(&self, ddx: Vector2<f32>, ddy: Vector2<f32>) -> f32 {
+        let size = Vector2::new(self.width as f32, self.height as f32);
+        let ddx = ddx.mul_element_wise(size);
+        let ddy = ddy.mul_element_wise(size);
+
+        0.5 * ddx.magnitude2().max(ddy.magnitude2()).log2()
+    }
+
+    /// Alpha of every texel of the full resolution image
+    pub(crate) fn alphas(&self) -> impl Iterator<Item = u8> + '_ {
+        self.levels[0].pixels.iter().map(|p| p.w)
+    }
+
+    /// RGBA color of the texel of the full resolution image in the [0, 1] range,
+    /// the coordinates can lie outside of the texture
+    pub fn get_pixel(&self, x: i64, y: i64) -> Vector4<f32> {
+        self.texel(0, x, y)
+    }
+
+    /// Looks up a texel of the mip level, applying the wrap modes to the coordinates
+    fn texel(&self, level: usize, x: i64, y: i64) -> Vector4<f32> {
+        let level = &self.levels[level];
 
-        self.pixels[index]
+        let x = self.wrap_u.apply(x, level.width);
+        let y = self.wrap_v.apply(y, level.height);
+        let (Some(x), Some(y)) = (x, y) else {
+            return self.border_color;
+        };
+
+        let col = level.pixels[(y * level.width as i64 + x) as usize];
+        self.color_space.decode(col)
+    }
+
+    fn nearest(&self, level: usize, texcoords: Vector2<f32>) -> Vector4<f32> {
+        let t = self.levels[level].texel_coords(texcoords);
+        self.texel(level, t.x.floor() as i64, t.y.floor() as i64)
+    }
+
+    fn bilinear(&self, level: usize, texcoords: Vector2<f32>) -> Vector4<f32> {
+        // Texel centers lie at half-integer coordinates
+        let t = self.levels[level].texel_coords(texcoords) - Vector2::new(0.5, 0.5);
+        let x = t.x.floor();
+        let y = t.y.floor();
+        let fx = t.x - x;
+        let fy = t.y - y;
+        let (x, y) = (x as i64, y as i64);
+
+        let texel = |x, y| self.texel(level, x, y);
+        let top = texel(x, y) * (1. - fx) + texel(x + 1, y) * fx;
+        let bottom = texel(x, y + 1) * (1. - fx) + texel(x + 1, y + 1) * fx;
+
+        top * (1. - fy) + bottom * fy
+    }
+}
+
+impl Default for Texture {
+    fn default() -> Self {
+        Self::new(
+            vec![Vector4::new(255, 255, 255, 255)],
+            1,
+            1,
+            ColorSpace::Linear,
+        )
+    }
+}
+
+/// Single level of the mip chain, the rows are stored from the top of the image
+struct MipLevel {
+    pixels: Vec<Vector4<u8>>,
+    width: u32,
+    height: u32,
+}
+
+impl MipLevel {
+    /// Halves the resolution by averaging blocks of 2x2 texels
+    fn downsample(&self, color_space: ColorSpace) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                // Odd sizes repeat the last row or column
+                let mut sum = Vector4::new(0., 0., 0., 0.);
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    sum += color_space.decode(self.pixels[(sy * self.width + sx) as usize]);
+                }
+
+                pixels.push(color_space.encode(sum / 4.));
+            }
+        }
+
+        MipLevel {
+            pixels,
+            width,
+            height,
+        }
+    }
+
+    /// Texel coordinates of the texture coordinates, the v axis points up
+    fn texel_coords(&self, texcoords: Vector2<f32>) -> Vector2<f32> {
+        Vector2::new(
+            texcoords.x * self.width as f32,
+            (1. - texcoords.y) * self.height as f32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(wrap: Wrap) -> Vec<Option<i64>> {
+        (-5..9).map(|coord| wrap.apply(coord, 4)).collect()
+    }
+
+    #[test]
+    fn wrap_repeat() {
+        let expected = [3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0];
+        assert_eq!(wrapped(Wrap::Repeat), expected.map(Some));
+    }
+
+    #[test]
+    fn wrap_mirrored_repeat() {
+        let expected = [3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0];
+        assert_eq!(wrapped(Wrap::MirroredRepeat), expected.map(Some));
+    }
+
+    #[test]
+    fn wrap_clamp_to_edge() {
+        let expected = [0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3];
+        assert_eq!(wrapped(Wrap::ClampToEdge), expected.map(Some));
+    }
+
+    #[test]
+    fn wrap_border() {
+        let expected: Vec<_> = [None; 5]
+            .into_iter()
+            .chain([0, 1, 2, 3].map(Some))
+            .chain([None; 5])
+            .collect();
+        assert_eq!(wrapped(Wrap::Border), expected);
     }
 }